@@ -0,0 +1,552 @@
+//! Stand-in servers for testing API clients and the bot.
+
+use flume::{Receiver, Sender};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tungstenite::Message;
+
+/// A request received by the mock server.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// The path including the query string.
+    pub path: String,
+    pub authorization: Option<String>,
+    pub body: Value,
+}
+
+fn read_request(stream: &TcpStream) -> std::io::Result<Request> {
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut authorization = None;
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            match name.to_ascii_lowercase().as_str() {
+                "authorization" => authorization = Some(value.trim().to_string()),
+                "content-length" => content_length = value.trim().parse().unwrap_or(0),
+                _ => (),
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Request {
+        method,
+        path,
+        authorization,
+        body: serde_json::from_slice(&body).unwrap_or(Value::Null),
+    })
+}
+
+fn write_response(stream: &TcpStream, status: u16, body: &Value) -> std::io::Result<()> {
+    write_raw_response(
+        stream,
+        status,
+        "application/json",
+        body.to_string().as_bytes(),
+    )
+}
+
+fn write_raw_response(
+    stream: &TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> std::io::Result<()> {
+    write_response_with_length(stream, status, content_type, body, true)
+}
+
+/// Write a response, leaving out the Content-Length header unless `content_length` is set. The
+/// body then ends when the connection is closed.
+fn write_response_with_length(
+    mut stream: &TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+    content_length: bool,
+) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status} Mock\r\nContent-Type: {content_type}\r\n"
+    )?;
+    if content_length {
+        write!(stream, "Content-Length: {}\r\n", body.len())?;
+    }
+    write!(stream, "Connection: close\r\n\r\n")?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+/// Serves a fixed list of responses in order, one per connection, and records the requests.
+pub struct MockHttp {
+    url: String,
+    server: JoinHandle<Vec<Request>>,
+}
+
+impl MockHttp {
+    pub fn start(responses: Vec<(u16, Value)>) -> Self {
+        Self::start_raw(
+            responses
+                .into_iter()
+                .map(|(status, body)| (status, "application/json", body.to_string().into_bytes()))
+                .collect(),
+        )
+    }
+
+    /// Serve responses with any content type.
+    pub fn start_raw(responses: Vec<(u16, &'static str, Vec<u8>)>) -> Self {
+        Self::serve(responses, true)
+    }
+
+    /// Like [`MockHttp::start_raw`], but without saying how long the responses are.
+    pub fn start_unsized(responses: Vec<(u16, &'static str, Vec<u8>)>) -> Self {
+        Self::serve(responses, false)
+    }
+
+    fn serve(responses: Vec<(u16, &'static str, Vec<u8>)>, content_length: bool) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let server = thread::spawn(move || {
+            let mut requests = vec![];
+            for (status, content_type, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                requests.push(read_request(&stream).unwrap());
+                write_response_with_length(&stream, status, content_type, &body, content_length)
+                    .unwrap();
+            }
+            requests
+        });
+
+        Self { url, server }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Wait until all responses are served, and return the requests that were received.
+    pub fn requests(self) -> Vec<Request> {
+        self.server.join().unwrap()
+    }
+}
+
+/// Everything the bot sent to the mock üWave server.
+#[derive(Debug, Default)]
+struct Recorded {
+    requests: Vec<Request>,
+    chat: Vec<String>,
+    /// Number of socket connections that were authenticated.
+    connections: usize,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    now: Mutex<Value>,
+    /// History entries with their full media inline, most recent first.
+    history: Mutex<Vec<Value>>,
+    recorded: Mutex<Recorded>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn record(&self, update: impl FnOnce(&mut Recorded)) {
+        update(&mut self.recorded.lock().unwrap());
+        self.changed.notify_all();
+    }
+
+    /// Wait until `find` returns something from the recorded data.
+    fn wait_for<T>(&self, what: &str, mut find: impl FnMut(&Recorded) -> Option<T>) -> T {
+        let deadline = Instant::now() + TIMEOUT;
+        let mut recorded = self.recorded.lock().unwrap();
+        loop {
+            if let Some(found) = find(&recorded) {
+                return found;
+            }
+            let remaining = deadline
+                .checked_duration_since(Instant::now())
+                .unwrap_or_else(|| panic!("timed out waiting for {}: {:#?}", what, recorded));
+            recorded = self.changed.wait_timeout(recorded, remaining).unwrap().0;
+        }
+    }
+
+    fn history_response(&self, path: &str) -> Value {
+        let media_filter = path
+            .split_once("filter%5Bmedia%5D=")
+            .or_else(|| path.split_once("filter[media]="))
+            .map(|(_, rest)| rest.split('&').next().unwrap_or_default().to_string());
+
+        let mut data = vec![];
+        let mut included = vec![];
+        for entry in self.history.lock().unwrap().iter() {
+            let media = &entry["media"]["media"];
+            if media_filter.as_ref().is_some_and(|id| media["_id"] != **id) {
+                continue;
+            }
+            let mut entry = entry.clone();
+            entry["media"]["media"] = media["_id"].clone();
+            data.push(entry);
+            included.push(media.clone());
+        }
+
+        json!({
+            "data": data,
+            "links": { "self": path, "next": null },
+            "meta": { "offset": 0, "pageSize": data.len(), "results": data.len(), "total": data.len() },
+            "included": { "media": included },
+        })
+    }
+
+    fn respond(&self, request: &Request) -> (u16, Value) {
+        let path = request.path.split('?').next().unwrap_or_default();
+        match (request.method.as_str(), path) {
+            ("POST", "/auth/login") => (200, json!({ "meta": { "jwt": "mock-jwt" } })),
+            ("GET", "/now") => (200, self.now.lock().unwrap().clone()),
+            ("GET", "/booth/history") => (200, self.history_response(&request.path)),
+            ("GET", path) if path.starts_with("/users/") => {
+                let user_id = &path["/users/".len()..];
+                let now = self.now.lock().unwrap();
+                let users = now["users"].as_array().cloned().unwrap_or_default();
+                match users.into_iter().find(|user| user["_id"] == user_id) {
+                    Some(user) => (200, json!({ "data": user })),
+                    None => (404, json!({ "errors": [{ "title": "User not found" }] })),
+                }
+            }
+            _ => (200, json!({ "data": {} })),
+        }
+    }
+}
+
+/// How long to wait for the bot to do something before failing a test.
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the mock socket sends keepalive messages.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(50);
+const SOCKET_TOKEN: &str = "mock-socket-token";
+
+fn serve_http(listener: TcpListener, shared: Arc<Shared>, stop: Arc<AtomicBool>) {
+    listener.set_nonblocking(true).unwrap();
+    while !stop.load(Ordering::Relaxed) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(5));
+                continue;
+            }
+            Err(err) => panic!("mock http server failed: {}", err),
+        };
+        stream.set_nonblocking(false).unwrap();
+        let Ok(request) = read_request(&stream) else {
+            continue;
+        };
+        let (status, body) = shared.respond(&request);
+        shared.record(|recorded| recorded.requests.push(request));
+        let _ = write_response(&stream, status, &body);
+    }
+}
+
+fn serve_socket(
+    listener: TcpListener,
+    shared: Arc<Shared>,
+    stop: Arc<AtomicBool>,
+    events: Receiver<String>,
+) {
+    listener.set_nonblocking(true).unwrap();
+    while !stop.load(Ordering::Relaxed) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(5));
+                continue;
+            }
+            Err(err) => panic!("mock socket server failed: {}", err),
+        };
+        stream.set_nonblocking(false).unwrap();
+        let Ok(mut socket) = tungstenite::accept(stream) else {
+            continue;
+        };
+        socket
+            .get_ref()
+            .set_read_timeout(Some(Duration::from_millis(5)))
+            .unwrap();
+
+        let mut authenticated = false;
+        let mut last_keepalive = Instant::now();
+        while !stop.load(Ordering::Relaxed) {
+            match socket.read_message() {
+                Ok(Message::Text(message)) if !authenticated => {
+                    assert_eq!(
+                        message, SOCKET_TOKEN,
+                        "socket authenticated with wrong token"
+                    );
+                    authenticated = true;
+                    socket
+                        .write_message(Message::Text(
+                            json!({ "command": "authenticated", "data": {} }).to_string(),
+                        ))
+                        .unwrap();
+                    shared.record(|recorded| recorded.connections += 1);
+                }
+                Ok(Message::Text(message)) => {
+                    let message: Value = serde_json::from_str(&message).unwrap();
+                    match message["command"].as_str() {
+                        Some("sendChat") => {
+                            let text = message["data"].as_str().unwrap_or_default().to_string();
+                            shared.record(|recorded| recorded.chat.push(text));
+                        }
+                        Some("logout") => break,
+                        _ => (),
+                    }
+                }
+                Ok(Message::Close(_)) => break,
+                Ok(_) => (),
+                Err(tungstenite::Error::Io(err))
+                    if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(_) => break,
+            }
+
+            if authenticated {
+                while let Ok(event) = events.try_recv() {
+                    socket.write_message(Message::Text(event)).unwrap();
+                }
+                if last_keepalive.elapsed() >= KEEPALIVE_INTERVAL {
+                    socket.write_message(Message::Text("-".into())).unwrap();
+                    last_keepalive = Instant::now();
+                }
+            }
+        }
+    }
+}
+
+/// A mock üWave server, with an HTTP API and a socket, for end-to-end tests of the bot.
+///
+/// The HTTP API implements `auth/login`, `now`, `booth/history` and `users/:id`. Other requests
+/// get an empty successful response. All requests are recorded, as are chat messages sent over
+/// the socket.
+pub struct MockUwave {
+    api_url: String,
+    socket_url: String,
+    shared: Arc<Shared>,
+    events: Sender<String>,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl MockUwave {
+    /// Start a server where `users` are online. The bot itself is added as the first user.
+    pub fn start(users: Value) -> Self {
+        let http = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let api_url = format!("http://{}", http.local_addr().unwrap());
+        let socket_url = format!("ws://{}", socket.local_addr().unwrap());
+
+        let bot = json!({ "_id": "bot", "username": "SekshiBot", "roles": ["user"] });
+        let mut online = vec![bot.clone()];
+        online.extend(users.as_array().cloned().unwrap_or_default());
+        let shared = Arc::new(Shared {
+            now: Mutex::new(json!({
+                "user": bot,
+                "users": online,
+                "guests": 0,
+                "roles": {
+                    "admin": ["*"],
+                    "manager": ["moderator"],
+                    "moderator": ["user"],
+                    "user": [],
+                },
+                "booth": null,
+                "waitlist": [],
+                "waitlistLocked": false,
+                "motd": null,
+                "socketToken": SOCKET_TOKEN,
+            })),
+            ..Default::default()
+        });
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let (events, event_receiver) = flume::unbounded();
+        let threads = vec![
+            thread::spawn({
+                let shared = Arc::clone(&shared);
+                let stop = Arc::clone(&stop);
+                move || serve_http(http, shared, stop)
+            }),
+            thread::spawn({
+                let shared = Arc::clone(&shared);
+                let stop = Arc::clone(&stop);
+                move || serve_socket(socket, shared, stop, event_receiver)
+            }),
+        ];
+
+        Self {
+            api_url,
+            socket_url,
+            shared,
+            events,
+            stop,
+            threads,
+        }
+    }
+
+    pub fn api_url(&self) -> &str {
+        &self.api_url
+    }
+
+    pub fn socket_url(&self) -> &str {
+        &self.socket_url
+    }
+
+    /// Replace the booth history. Entries have their full media inline, most recent first.
+    pub fn set_history(&self, history: Vec<Value>) {
+        *self.shared.history.lock().unwrap() = history;
+    }
+
+    /// Run a scenario against the bot, returning the HTTP requests matched by its expectations.
+    pub fn run(&self, scenario: Scenario) -> Vec<Request> {
+        let mut matched = vec![];
+        // Expectations only look at what happened after the previous expectation matched.
+        let mut seen_requests = 0;
+        let mut seen_chat = 0;
+        for step in scenario.steps {
+            match step {
+                Step::Connected => {
+                    self.shared.wait_for("a socket connection", |recorded| {
+                        (recorded.connections > 0).then_some(())
+                    });
+                }
+                Step::Emit(event) => self.events.send(event).unwrap(),
+                Step::ExpectRequest { method, path } => {
+                    let (index, request) =
+                        self.shared
+                            .wait_for(&format!("request {method} {path}"), |recorded| {
+                                recorded
+                                    .requests
+                                    .iter()
+                                    .enumerate()
+                                    .skip(seen_requests)
+                                    .find(|(_, request)| {
+                                        request.method == method
+                                            && request.path.split('?').next() == Some(path)
+                                    })
+                                    .map(|(index, request)| (index, request.clone()))
+                            });
+                    seen_requests = index + 1;
+                    matched.push(request);
+                }
+                Step::ExpectChat(text) => {
+                    seen_chat = self.shared.wait_for(
+                        &format!("chat message containing {text:?}"),
+                        |recorded| {
+                            recorded
+                                .chat
+                                .iter()
+                                .enumerate()
+                                .skip(seen_chat)
+                                .find(|(_, message)| message.contains(text))
+                                .map(|(index, _)| index + 1)
+                        },
+                    );
+                }
+            }
+        }
+        matched
+    }
+
+    /// All chat messages the bot sent so far.
+    pub fn chat(&self) -> Vec<String> {
+        self.shared.recorded.lock().unwrap().chat.clone()
+    }
+}
+
+impl Drop for MockUwave {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+enum Step {
+    Connected,
+    Emit(String),
+    ExpectRequest {
+        method: &'static str,
+        path: &'static str,
+    },
+    ExpectChat(&'static str),
+}
+
+/// A script of socket events to send to the bot, and things the bot should do in response.
+///
+/// ```ignore
+/// server.run(
+///     Scenario::new()
+///         .emit("advance", json!({ ... }))
+///         .expect_request("POST", "/booth/skip")
+///         .expect_chat("This song was played"),
+/// );
+/// ```
+#[derive(Default)]
+pub struct Scenario {
+    steps: Vec<Step>,
+}
+
+impl Scenario {
+    /// Start a scenario. It waits until the bot connected to the socket.
+    pub fn new() -> Self {
+        Self {
+            steps: vec![Step::Connected],
+        }
+    }
+
+    /// Send a socket event to the bot.
+    pub fn emit(mut self, command: &str, data: Value) -> Self {
+        let event = json!({ "command": command, "data": data });
+        self.steps.push(Step::Emit(event.to_string()));
+        self
+    }
+
+    /// Send a chat message from a user to the bot.
+    pub fn chat(self, user_id: &str, message: &str) -> Self {
+        self.emit(
+            "chatMessage",
+            json!({
+                "id": format!("{user_id}-{message}"),
+                "userID": user_id,
+                "message": message,
+                "timestamp": 0,
+            }),
+        )
+    }
+
+    /// Wait until the bot makes an HTTP request. `path` does not include the query string.
+    pub fn expect_request(mut self, method: &'static str, path: &'static str) -> Self {
+        self.steps.push(Step::ExpectRequest { method, path });
+        self
+    }
+
+    /// Wait until the bot sends a chat message containing `text`.
+    pub fn expect_chat(mut self, text: &'static str) -> Self {
+        self.steps.push(Step::ExpectChat(text));
+        self
+    }
+}
@@ -29,13 +29,16 @@ struct ListResponse {
 }
 
 /// Returns the URL to the page.
-pub fn publish(page_name: &str, content: &str) -> Result<String, PublishError> {
-    let username = std::env::var("NEOCITIES_USERNAME").map_err(|_| PublishError::MissingAuth)?;
-    let password = std::env::var("NEOCITIES_PASSWORD").map_err(|_| PublishError::MissingAuth)?;
-    let authorization = format!(
-        "Basic {}",
-        base64::encode(format!("{username}:{password}"))
-    );
+pub fn publish(
+    username: &str,
+    password: &str,
+    page_name: &str,
+    content: &str,
+) -> Result<String, PublishError> {
+    if username.is_empty() || password.is_empty() {
+        return Err(PublishError::MissingAuth);
+    }
+    let authorization = format!("Basic {}", base64::encode(format!("{username}:{password}")));
 
     let client = AgentBuilder::new().build();
 
@@ -1,14 +1,76 @@
+use crate::handler::MessageType;
 use chrono::{DateTime, Utc};
 use serde::Deserialize;
 use serde_json::json;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::{self, Debug, Formatter};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use thiserror::Error;
-use ureq::Agent;
+use ureq::{Agent, Response};
 
 #[derive(Debug, Error)]
 #[error("JWT missing or expired")]
 pub struct UnauthorizedError;
 
+/// An error response from the üWave HTTP API, other than 401 Unauthorized.
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("rate limited")]
+    RateLimited { retry_after: Option<Duration> },
+    #[error("server error {status}: {message}")]
+    ServerError { status: u16, message: String },
+    #[error("unexpected status {status}: {message}")]
+    Status { status: u16, message: String },
+}
+
+impl ApiError {
+    fn from_status(status: u16, message: String, retry_after: Option<&str>) -> Self {
+        match status {
+            403 => Self::Forbidden(message),
+            404 => Self::NotFound(message),
+            429 => Self::RateLimited {
+                retry_after: retry_after
+                    .and_then(|seconds| seconds.trim().parse().ok())
+                    .map(Duration::from_secs),
+            },
+            500..=599 => Self::ServerError { status, message },
+            _ => Self::Status { status, message },
+        }
+    }
+}
+
+/// Convert a ureq error into a typed error where possible.
+fn classify_error(err: Box<ureq::Error>) -> anyhow::Error {
+    #[derive(Debug, Deserialize)]
+    struct ErrorDetail {
+        title: String,
+    }
+    #[derive(Debug, Deserialize)]
+    struct ErrorResponse {
+        errors: Vec<ErrorDetail>,
+    }
+
+    match *err {
+        ureq::Error::Status(401, _) => UnauthorizedError.into(),
+        ureq::Error::Status(status, response) => {
+            let retry_after = response.header("retry-after").map(ToOwned::to_owned);
+            let status_text = response.status_text().to_string();
+            let message = response
+                .into_json::<ErrorResponse>()
+                .ok()
+                .and_then(|body| body.errors.into_iter().next())
+                .map_or(status_text, |error| error.title);
+            ApiError::from_status(status, message, retry_after.as_deref()).into()
+        }
+        err => err.into(),
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct BaseMedia {
     #[serde(rename = "_id")]
@@ -31,6 +93,172 @@ pub struct MediaWithOverrides<T> {
     pub end: u32,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct User {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub username: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub avatar: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct VoteStats {
+    #[serde(default)]
+    pub upvotes: Vec<String>,
+    #[serde(default)]
+    pub downvotes: Vec<String>,
+    #[serde(default)]
+    pub favorites: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Booth {
+    #[serde(rename = "historyID")]
+    pub history_id: String,
+    #[serde(rename = "userID")]
+    pub user_id: String,
+    pub media: MediaWithOverrides<BaseMedia>,
+    #[serde(rename = "playedAt")]
+    pub played_at: u64,
+    #[serde(default)]
+    pub stats: VoteStats,
+}
+
+/// The state of the room, as returned by the `now` endpoint and kept up to date by socket events.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct NowState {
+    /// The signed-in user, ie. the bot.
+    #[serde(default)]
+    pub user: Option<User>,
+    /// Users that are currently online.
+    #[serde(default)]
+    pub users: Vec<User>,
+    #[serde(default)]
+    pub guests: i64,
+    /// Maps role names to the roles and permissions they include.
+    #[serde(default)]
+    pub roles: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub booth: Option<Booth>,
+    #[serde(default)]
+    pub waitlist: Vec<String>,
+    #[serde(default, rename = "waitlistLocked")]
+    pub waitlist_locked: bool,
+    #[serde(default)]
+    pub motd: Option<String>,
+    #[serde(default, rename = "socketToken")]
+    pub socket_token: Option<String>,
+}
+
+impl NowState {
+    /// Find an online user by ID.
+    pub fn find_user(&self, user_id: &str) -> Option<&User> {
+        self.users.iter().find(|user| user.id == user_id)
+    }
+
+    /// The user who is currently playing.
+    pub fn current_dj(&self) -> Option<&User> {
+        self.find_user(&self.booth.as_ref()?.user_id)
+    }
+
+    /// The media that is currently playing.
+    pub fn current_media(&self) -> Option<&MediaWithOverrides<BaseMedia>> {
+        self.booth.as_ref().map(|booth| &booth.media)
+    }
+
+    /// Update the state for a socket event.
+    pub fn apply(&mut self, message: &MessageType) {
+        match message {
+            MessageType::Reconnected(now) => *self = NowState::clone(now),
+            MessageType::Guests { count } => self.guests = *count,
+            MessageType::Advance(advance) => {
+                self.booth = Some(Booth {
+                    history_id: advance.history_id.clone(),
+                    user_id: advance.user_id.clone(),
+                    media: advance.media.clone(),
+                    played_at: advance.played_at,
+                    stats: VoteStats::default(),
+                });
+            }
+            MessageType::BoothEmpty => self.booth = None,
+            MessageType::Motd { motd } => self.motd = motd.clone(),
+            MessageType::WaitlistUpdate { user_ids } => self.waitlist = user_ids.clone(),
+            MessageType::WaitlistJoin(update)
+            | MessageType::WaitlistLeave(update)
+            | MessageType::WaitlistAdd(update)
+            | MessageType::WaitlistRemove(update)
+            | MessageType::WaitlistMove(update) => self.waitlist = update.waitlist.clone(),
+            MessageType::WaitlistLock { locked } => self.waitlist_locked = *locked,
+            MessageType::WaitlistClear { .. } => self.waitlist.clear(),
+            MessageType::Vote { user_id, value } => {
+                if let Some(booth) = &mut self.booth {
+                    let stats = &mut booth.stats;
+                    stats.upvotes.retain(|id| id != user_id);
+                    stats.downvotes.retain(|id| id != user_id);
+                    match value {
+                        1 => stats.upvotes.push(user_id.clone()),
+                        -1 => stats.downvotes.push(user_id.clone()),
+                        _ => (),
+                    }
+                }
+            }
+            MessageType::Favorite {
+                user_id,
+                history_id,
+            } => {
+                if let Some(booth) = &mut self.booth {
+                    if booth.history_id == *history_id && !booth.stats.favorites.contains(user_id) {
+                        booth.stats.favorites.push(user_id.clone());
+                    }
+                }
+            }
+            MessageType::UserJoin(user) => {
+                self.users.retain(|existing| existing.id != user.id);
+                self.users.push(User::clone(user));
+            }
+            MessageType::UserLeave { user_id } => self.users.retain(|user| user.id != *user_id),
+            MessageType::NameChange { user_id, username } => {
+                self.for_user(user_id, |user| user.username = username.clone());
+            }
+            MessageType::RolesAdded { user_id, roles } => {
+                self.for_user(user_id, |user| {
+                    for role in roles {
+                        if !user.roles.contains(role) {
+                            user.roles.push(role.clone());
+                        }
+                    }
+                });
+            }
+            MessageType::RolesRemoved { user_id, roles } => {
+                self.for_user(user_id, |user| {
+                    user.roles.retain(|role| !roles.contains(role))
+                });
+            }
+            MessageType::Authenticated
+            | MessageType::Skip(_)
+            | MessageType::ChatMessage(_)
+            | MessageType::ChatDelete { .. }
+            | MessageType::ChatDeleteById { .. }
+            | MessageType::ChatDeleteByUser { .. }
+            | MessageType::ChatMute(_)
+            | MessageType::ChatUnmute { .. }
+            | MessageType::Ban(_)
+            | MessageType::Unban { .. } => (),
+        }
+    }
+
+    /// Update the known copies of a user: in the online users list, and the bot user itself.
+    fn for_user(&mut self, user_id: &str, mut update: impl FnMut(&mut User)) {
+        let online = self.users.iter_mut().filter(|user| user.id == user_id);
+        for user in online.chain(self.user.iter_mut().filter(|user| user.id == user_id)) {
+            update(user);
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 struct Links {
     #[serde(rename = "self")]
@@ -56,6 +284,11 @@ struct ResponseData<Data, Meta, Included> {
     pub included: Included,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+struct SingleResponse<Data> {
+    pub data: Data,
+}
+
 #[derive(Debug, Clone)]
 pub struct Pagination {
     pub offset: u32,
@@ -74,9 +307,24 @@ impl Default for Pagination {
 #[derive(Debug, Clone, Default)]
 pub struct HistoryOptions {
     pub media: Option<String>,
+    /// Only include plays by this user.
+    pub user: Option<String>,
+    /// Only include plays at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Only include plays before this time.
+    pub until: Option<DateTime<Utc>>,
     pub pagination: Option<Pagination>,
 }
 
+impl HistoryOptions {
+    /// Check the filters that the server does not support.
+    fn matches<T>(&self, entry: &HistoryEntry<T>) -> bool {
+        self.user.as_ref().is_none_or(|user| entry.user_id == *user)
+            && self.since.is_none_or(|since| entry.played_at >= since)
+            && self.until.is_none_or(|until| entry.played_at < until)
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct SkipOptions {
     pub user_id: String,
@@ -84,12 +332,38 @@ pub struct SkipOptions {
     pub remove: bool,
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct LeaveBoothOptions {
+    /// The user to remove from the booth. Defaults to the bot itself.
+    pub user_id: Option<String>,
+    /// Leave after the current play instead of immediately.
+    pub auto_leave: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BanOptions {
+    pub user_id: String,
+    /// How long the ban lasts. Bans without a duration are permanent.
+    pub duration: Option<Duration>,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct WaitlistLockData {
+    locked: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct MotdData {
+    motd: Option<String>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct HistoryEntry<TMedia> {
     pub media: MediaWithOverrides<TMedia>,
-    upvotes: Vec<String>,
-    downvotes: Vec<String>,
-    favorites: Vec<String>,
+    pub upvotes: Vec<String>,
+    pub downvotes: Vec<String>,
+    pub favorites: Vec<String>,
     #[serde(rename = "_id")]
     pub history_id: String,
     #[serde(rename = "user")]
@@ -98,84 +372,461 @@ pub struct HistoryEntry<TMedia> {
     pub played_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Deserialize)]
+struct IncludeHistory {
+    media: Vec<BaseMedia>,
+}
+
+type HistoryResponseShape = ResponseData<Vec<HistoryEntry<String>>, PageMeta, IncludeHistory>;
+
+struct HistoryPage {
+    entries: Vec<HistoryEntry<BaseMedia>>,
+    /// The next page, if there is one.
+    next: Option<Pagination>,
+}
+
+impl HistoryPage {
+    fn from_response(response: HistoryResponseShape) -> Self {
+        let ResponseData {
+            data,
+            links,
+            meta,
+            included,
+        } = response;
+
+        let next = links.next.map(|_| Pagination {
+            offset: meta.offset + meta.page_size,
+            limit: meta.page_size,
+        });
+
+        // Fill in the `media.media` properties with the actual media
+        let entries = data
+            .into_iter()
+            .filter_map(|entry| {
+                let Some(media) = included
+                    .media
+                    .iter()
+                    .find(|media| media.id == entry.media.media)
+                else {
+                    log::warn!(
+                        "history entry {} refers to missing media {}",
+                        entry.history_id,
+                        entry.media.media
+                    );
+                    return None;
+                };
+                Some(HistoryEntry {
+                    media: MediaWithOverrides {
+                        media: media.clone(),
+                        artist: entry.media.artist,
+                        title: entry.media.title,
+                        start: entry.media.start,
+                        end: entry.media.end,
+                    },
+                    upvotes: entry.upvotes,
+                    downvotes: entry.downvotes,
+                    favorites: entry.favorites,
+                    history_id: entry.history_id,
+                    user_id: entry.user_id,
+                    played_at: entry.played_at,
+                })
+            })
+            .collect();
+
+        Self { entries, next }
+    }
+}
+
+/// Lazily paginating iterator over the booth history, created by [`HttpApi::history_iter`].
+pub struct HistoryIter<'a> {
+    api: &'a HttpApi,
+    opts: HistoryOptions,
+    entries: VecDeque<HistoryEntry<BaseMedia>>,
+    next: Option<Pagination>,
+}
+
+impl Iterator for HistoryIter<'_> {
+    type Item = anyhow::Result<HistoryEntry<BaseMedia>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.pop_front() {
+                // History is sorted by time, so there is nothing left to find.
+                if self.opts.since.is_some_and(|since| entry.played_at < since) {
+                    self.entries.clear();
+                    self.next = None;
+                    return None;
+                }
+                if self.opts.matches(&entry) {
+                    return Some(Ok(entry));
+                }
+                continue;
+            }
+
+            let pagination = self.next.take()?;
+            match self
+                .api
+                .history_page(self.opts.media.as_deref(), &pagination)
+            {
+                Ok(page) => {
+                    self.entries = page.entries.into();
+                    // Stop if the server returns an empty page, to avoid looping forever.
+                    if !self.entries.is_empty() {
+                        self.next = page.next;
+                    }
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Credentials {
+    email: String,
+    password: String,
+}
+
 #[derive(Clone)]
 pub struct HttpApi {
     client: Agent,
     api_url: String,
-    auth: String,
+    auth: Arc<RwLock<String>>,
+    credentials: Option<Credentials>,
 }
 
 impl HttpApi {
-    pub fn new(client: Agent, api_url: String, auth: String) -> Self {
+    /// Sign in with an email and password. The credentials are kept so the session can be renewed
+    /// when it expires.
+    pub fn login(
+        client: Agent,
+        api_url: String,
+        email: String,
+        password: String,
+    ) -> anyhow::Result<Self> {
+        let api = Self {
+            client,
+            api_url,
+            auth: Default::default(),
+            credentials: Some(Credentials { email, password }),
+        };
+        api.sign_in()?;
+        Ok(api)
+    }
+
+    /// Use the API without signing in. Requests that need a session fail with
+    /// [`UnauthorizedError`].
+    pub fn anonymous(client: Agent, api_url: String) -> Self {
         Self {
             client,
             api_url,
-            auth,
+            auth: Default::default(),
+            credentials: None,
         }
     }
 
+    /// The HTTP client, for requests to other servers.
+    pub fn agent(&self) -> &Agent {
+        &self.client
+    }
+
     fn url(&self, endpoint: &str) -> String {
         format!("{}/{}", &self.api_url, endpoint)
     }
 
-    pub fn history(&self, opts: HistoryOptions) -> anyhow::Result<Vec<HistoryEntry<BaseMedia>>> {
-        let mut req = self.client.get(&self.url("booth/history"));
-        if let Some(id) = opts.media {
-            req = req.query("filter[media]", &id);
-        }
+    /// Request a new JWT using the stored credentials.
+    pub fn sign_in(&self) -> anyhow::Result<()> {
+        let Some(Credentials { email, password }) = &self.credentials else {
+            return Err(UnauthorizedError.into());
+        };
+
+        log::info!("signing in...");
+        let login = self
+            .client
+            .post(&self.url("auth/login"))
+            .send_json(json!({
+                "email": email,
+                "password": password,
+            }))
+            .map_err(|err| classify_error(Box::new(err)))?
+            .into_json::<serde_json::Value>()?;
 
-        #[derive(Debug, Deserialize)]
-        struct IncludeHistory {
-            media: Vec<BaseMedia>,
+        let jwt = if let Some(jwt) = login["meta"]["jwt"].as_str() {
+            jwt.to_string()
+        } else {
+            anyhow::bail!("no jwt found")
+        };
+        *self.auth.write().unwrap() = format!("JWT {jwt}");
+
+        Ok(())
+    }
+
+    /// Send a request with the current authorization. If the session expired, sign in again and
+    /// retry once.
+    fn send(
+        &self,
+        method: &str,
+        endpoint: &str,
+        query: &[(&str, &str)],
+        body: Option<&serde_json::Value>,
+    ) -> anyhow::Result<Response> {
+        let attempt = || {
+            let auth = self.auth.read().unwrap().clone();
+            let mut req = self
+                .client
+                .request(method, &self.url(endpoint))
+                .set("Authorization", &auth);
+            for (param, value) in query {
+                req = req.query(param, value);
+            }
+            match body {
+                Some(body) => req.send_json(body),
+                None => req.call(),
+            }
+            .map_err(Box::new)
+        };
+
+        match attempt() {
+            Err(err)
+                if matches!(*err, ureq::Error::Status(401, _)) && self.credentials.is_some() =>
+            {
+                log::info!("session expired");
+                self.sign_in()?;
+                attempt().map_err(classify_error)
+            }
+            result => result.map_err(classify_error),
         }
+    }
 
-        type HistoryResponseShape =
-            ResponseData<Vec<HistoryEntry<String>>, PageMeta, IncludeHistory>;
+    /// Load the current state of the server, including a fresh socket token.
+    pub fn now(&self) -> anyhow::Result<NowState> {
+        let response = self.send("GET", "now", &[], None)?;
 
-        let response = req.call()?;
-        let ResponseData { data, included, .. } = response.into_json::<HistoryResponseShape>()?;
+        Ok(response.into_json()?)
+    }
 
-        // Fill in the `media.media` properties with the actual media
-        let entries = data
+    /// Look up a user by ID.
+    pub fn user(&self, user_id: &str) -> anyhow::Result<User> {
+        let response = self.send("GET", &format!("users/{user_id}"), &[], None)?;
+        let SingleResponse { data } = response.into_json()?;
+        Ok(data)
+    }
+
+    fn history_page(
+        &self,
+        media: Option<&str>,
+        pagination: &Pagination,
+    ) -> anyhow::Result<HistoryPage> {
+        let offset = pagination.offset.to_string();
+        let limit = pagination.limit.to_string();
+        let mut query = vec![
+            ("page[offset]", offset.as_str()),
+            ("page[limit]", limit.as_str()),
+        ];
+        if let Some(id) = media {
+            query.push(("filter[media]", id));
+        }
+
+        let response = self.send("GET", "booth/history", &query, None)?;
+        Ok(HistoryPage::from_response(response.into_json()?))
+    }
+
+    /// Get a single page of the booth history, most recent first.
+    pub fn history(&self, opts: HistoryOptions) -> anyhow::Result<Vec<HistoryEntry<BaseMedia>>> {
+        let pagination = opts.pagination.clone().unwrap_or_default();
+        let page = self.history_page(opts.media.as_deref(), &pagination)?;
+        Ok(page
+            .entries
             .into_iter()
-            .map(|entry| HistoryEntry {
-                media: MediaWithOverrides {
-                    media: included
-                        .media
-                        .iter()
-                        .find(|media| media.id == entry.media.media)
-                        .unwrap()
-                        .clone(),
-                    artist: entry.media.artist,
-                    title: entry.media.title,
-                    start: entry.media.start,
-                    end: entry.media.end,
-                },
-                upvotes: entry.upvotes,
-                downvotes: entry.downvotes,
-                favorites: entry.favorites,
-                history_id: entry.history_id,
-                user_id: entry.user_id,
-                played_at: entry.played_at,
-            })
-            .collect();
+            .filter(|entry| opts.matches(entry))
+            .collect())
+    }
 
-        Ok(entries)
+    /// Iterate over the booth history, most recent first. Pages are fetched as they are needed.
+    pub fn history_iter(&self, opts: HistoryOptions) -> HistoryIter<'_> {
+        let next = opts.pagination.clone().unwrap_or_default();
+        HistoryIter {
+            api: self,
+            opts,
+            entries: Default::default(),
+            next: Some(next),
+        }
     }
 
     pub fn skip(&self, opts: SkipOptions) -> anyhow::Result<()> {
-        let response = self
-            .client
-            .post(&self.url("booth/skip"))
-            .set("Authorization", &self.auth)
-            .send_json(json!({
+        let response = self.send(
+            "POST",
+            "booth/skip",
+            &[],
+            Some(&json!({
                 "reason": opts.reason.unwrap_or_default(),
                 "userID": opts.user_id,
                 "remove": opts.remove,
-            }))?;
+            })),
+        )?;
+
+        let _: serde_json::Value = response.into_json()?;
+
+        Ok(())
+    }
+
+    /// Remove a user from the booth, or the bot itself if no user is given.
+    pub fn leave_booth(&self, opts: LeaveBoothOptions) -> anyhow::Result<()> {
+        let mut body = json!({ "autoLeave": opts.auto_leave });
+        if let Some(user_id) = opts.user_id {
+            body["userID"] = json!(user_id);
+        }
+        let response = self.send("PUT", "booth/leave", &[], Some(&body))?;
+        let _: serde_json::Value = response.into_json()?;
+        Ok(())
+    }
+
+    /// Add a user to the end of the waitlist. Returns the new waitlist.
+    pub fn waitlist_add(&self, user_id: &str) -> anyhow::Result<Vec<String>> {
+        let response = self.send("POST", "waitlist", &[], Some(&json!({ "userID": user_id })))?;
+        let SingleResponse { data } = response.into_json()?;
+        Ok(data)
+    }
+
+    /// Move a user to a (0-based) position in the waitlist. Returns the new waitlist.
+    pub fn waitlist_move(&self, user_id: &str, position: usize) -> anyhow::Result<Vec<String>> {
+        let response = self.send(
+            "PUT",
+            "waitlist/move",
+            &[],
+            Some(&json!({ "userID": user_id, "position": position })),
+        )?;
+        let SingleResponse { data } = response.into_json()?;
+        Ok(data)
+    }
+
+    /// Remove a user from the waitlist. Returns the new waitlist.
+    pub fn waitlist_remove(&self, user_id: &str) -> anyhow::Result<Vec<String>> {
+        let response = self.send("DELETE", &format!("waitlist/{user_id}"), &[], None)?;
+        let SingleResponse { data } = response.into_json()?;
+        Ok(data)
+    }
+
+    /// Lock or unlock the waitlist. Returns whether the waitlist is now locked.
+    pub fn waitlist_lock(&self, locked: bool) -> anyhow::Result<bool> {
+        let response = self.send(
+            "PUT",
+            "waitlist/lock",
+            &[],
+            Some(&json!({ "lock": locked })),
+        )?;
+        let SingleResponse {
+            data: WaitlistLockData { locked },
+        } = response.into_json()?;
+        Ok(locked)
+    }
+
+    pub fn waitlist_clear(&self) -> anyhow::Result<()> {
+        let response = self.send("DELETE", "waitlist", &[], None)?;
+        let _: serde_json::Value = response.into_json()?;
+        Ok(())
+    }
+
+    /// Delete a single chat message.
+    pub fn delete_chat_message(&self, message_id: &str) -> anyhow::Result<()> {
+        let response = self.send("DELETE", &format!("chat/{message_id}"), &[], None)?;
+        let _: serde_json::Value = response.into_json()?;
+        Ok(())
+    }
+
+    /// Delete all chat messages sent by a user.
+    pub fn delete_chat_by_user(&self, user_id: &str) -> anyhow::Result<()> {
+        let response = self.send("DELETE", &format!("chat/user/{user_id}"), &[], None)?;
+        let _: serde_json::Value = response.into_json()?;
+        Ok(())
+    }
+
+    /// Delete all chat messages.
+    pub fn delete_all_chat(&self) -> anyhow::Result<()> {
+        let response = self.send("DELETE", "chat", &[], None)?;
+        let _: serde_json::Value = response.into_json()?;
+        Ok(())
+    }
+
+    /// Prevent a user from chatting for some time.
+    pub fn mute(&self, user_id: &str, duration: Duration) -> anyhow::Result<()> {
+        let response = self.send(
+            "POST",
+            &format!("users/{user_id}/mute"),
+            &[],
+            Some(&json!({ "time": duration.as_secs() })),
+        )?;
+        let _: serde_json::Value = response.into_json()?;
+        Ok(())
+    }
+
+    pub fn unmute(&self, user_id: &str) -> anyhow::Result<()> {
+        let response = self.send("DELETE", &format!("users/{user_id}/mute"), &[], None)?;
+        let _: serde_json::Value = response.into_json()?;
+        Ok(())
+    }
+
+    pub fn ban(&self, opts: BanOptions) -> anyhow::Result<()> {
+        let response = self.send(
+            "POST",
+            "bans",
+            &[],
+            Some(&json!({
+                "userID": opts.user_id,
+                "duration": opts.duration.map_or(0, |duration| duration.as_millis()),
+                "permanent": opts.duration.is_none(),
+                "reason": opts.reason.unwrap_or_default(),
+            })),
+        )?;
+        let _: serde_json::Value = response.into_json()?;
+        Ok(())
+    }
+
+    pub fn unban(&self, user_id: &str) -> anyhow::Result<()> {
+        let response = self.send("DELETE", &format!("bans/{user_id}"), &[], None)?;
+        let _: serde_json::Value = response.into_json()?;
+        Ok(())
+    }
+
+    /// Look up a user by their exact username, ignoring case.
+    pub fn user_by_name(&self, username: &str) -> anyhow::Result<Option<User>> {
+        let response = self.send("GET", "users", &[("filter", username)], None)?;
+        let SingleResponse { data } = response.into_json::<SingleResponse<Vec<User>>>()?;
+        Ok(data
+            .into_iter()
+            .find(|user| user.username.eq_ignore_ascii_case(username)))
+    }
 
+    pub fn add_role(&self, user_id: &str, role: &str) -> anyhow::Result<()> {
+        let response = self.send("PUT", &format!("users/{user_id}/roles/{role}"), &[], None)?;
         let _: serde_json::Value = response.into_json()?;
+        Ok(())
+    }
+
+    pub fn remove_role(&self, user_id: &str, role: &str) -> anyhow::Result<()> {
+        let response = self.send(
+            "DELETE",
+            &format!("users/{user_id}/roles/{role}"),
+            &[],
+            None,
+        )?;
+        let _: serde_json::Value = response.into_json()?;
+        Ok(())
+    }
+
+    /// Get the message of the day.
+    pub fn motd(&self) -> anyhow::Result<Option<String>> {
+        let response = self.send("GET", "motd", &[], None)?;
+        let SingleResponse {
+            data: MotdData { motd },
+        } = response.into_json()?;
+        Ok(motd)
+    }
 
+    /// Change the message of the day. `None` removes it.
+    pub fn set_motd(&self, motd: Option<&str>) -> anyhow::Result<()> {
+        let response = self.send("PUT", "motd", &[], Some(&json!({ "motd": motd })))?;
+        let _: serde_json::Value = response.into_json()?;
         Ok(())
     }
 }
@@ -189,3 +840,317 @@ impl Debug for HttpApi {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ApiError, BanOptions, HistoryOptions, HistoryPage, HttpApi, LeaveBoothOptions, NowState,
+        User,
+    };
+    use crate::api::mock::MockHttp;
+    use serde_json::json;
+    use std::time::Duration;
+    use ureq::AgentBuilder;
+
+    fn login(server: &MockHttp) -> HttpApi {
+        HttpApi::login(
+            AgentBuilder::new().build(),
+            server.url().to_string(),
+            "sekshibot@example.com".into(),
+            "hunter2".into(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_now() {
+        let now: NowState = serde_json::from_value(serde_json::json!({
+            "motd": "Welcome!",
+            "user": { "_id": "bot", "username": "SekshiBot", "roles": ["user"] },
+            "users": [
+                { "_id": "bot", "username": "SekshiBot", "roles": ["user"] },
+                { "_id": "dj", "username": "Someone", "roles": ["moderator"], "avatar": null },
+            ],
+            "guests": 3,
+            "roles": { "moderator": ["user", "booth.skip.other"], "user": [] },
+            "booth": {
+                "historyID": "h1",
+                "userID": "dj",
+                "playedAt": 1_660_000_000_000_u64,
+                "media": {
+                    "media": {
+                        "_id": "m1",
+                        "sourceType": "youtube",
+                        "sourceID": "abc",
+                        "artist": "IU",
+                        "title": "Blueming",
+                        "duration": 217,
+                    },
+                    "artist": "IU",
+                    "title": "Blueming",
+                    "start": 0,
+                    "end": 217,
+                },
+                "stats": { "upvotes": ["bot"], "downvotes": [], "favorites": [] },
+            },
+            "waitlist": ["bot"],
+            "waitlistLocked": false,
+            "socketToken": "token",
+        }))
+        .unwrap();
+
+        assert_eq!(now.socket_token.as_deref(), Some("token"));
+        assert_eq!(now.current_dj().unwrap().username, "Someone");
+        assert_eq!(now.current_media().unwrap().media.source_id, "abc");
+        assert_eq!(now.booth.unwrap().stats.upvotes, ["bot"]);
+        assert_eq!(now.roles["moderator"], ["user", "booth.skip.other"]);
+    }
+
+    #[test]
+    fn apply_events() {
+        use crate::handler::MessageType;
+
+        let mut now = NowState::default();
+        now.apply(&MessageType::UserJoin(Box::new(User {
+            id: "u1".into(),
+            username: "Someone".into(),
+            roles: vec!["user".into()],
+            avatar: None,
+        })));
+        now.apply(&MessageType::RolesAdded {
+            user_id: "u1".into(),
+            roles: vec!["moderator".into()],
+        });
+        now.apply(&MessageType::NameChange {
+            user_id: "u1".into(),
+            username: "Someone Else".into(),
+        });
+        assert_eq!(now.users[0].username, "Someone Else");
+        assert_eq!(now.users[0].roles, ["user", "moderator"]);
+
+        now.apply(&MessageType::WaitlistUpdate {
+            user_ids: vec!["u1".into()],
+        });
+        now.apply(&MessageType::WaitlistLock { locked: true });
+        assert_eq!(now.waitlist, ["u1"]);
+        assert!(now.waitlist_locked);
+
+        now.apply(&MessageType::UserLeave {
+            user_id: "u1".into(),
+        });
+        now.apply(&MessageType::WaitlistClear { moderator_id: None });
+        assert!(now.users.is_empty());
+        assert!(now.waitlist.is_empty());
+    }
+
+    #[test]
+    fn parse_history_page() {
+        let entry = |id: &str, user: &str, media: &str, played_at: &str| {
+            serde_json::json!({
+                "_id": id,
+                "user": user,
+                "media": { "media": media, "artist": "IU", "title": "Blueming", "start": 0, "end": 217 },
+                "playedAt": played_at,
+                "upvotes": [],
+                "downvotes": [],
+                "favorites": [],
+            })
+        };
+        let page = HistoryPage::from_response(
+            serde_json::from_value(serde_json::json!({
+                "data": [
+                    entry("h3", "u1", "m1", "2022-08-01T12:00:00Z"),
+                    entry("h2", "u2", "deleted", "2022-08-01T11:55:00Z"),
+                    entry("h1", "u2", "m1", "2022-08-01T11:50:00Z"),
+                ],
+                "links": { "self": "/booth/history", "next": "/booth/history?page[offset]=3" },
+                "meta": { "offset": 0, "pageSize": 3, "results": 3, "total": 10 },
+                "included": {
+                    "media": [{
+                        "_id": "m1",
+                        "sourceType": "youtube",
+                        "sourceID": "abc",
+                        "artist": "IU",
+                        "title": "Blueming",
+                        "duration": 217,
+                    }],
+                },
+            }))
+            .unwrap(),
+        );
+
+        // The entry with missing media is left out.
+        let ids: Vec<_> = page
+            .entries
+            .iter()
+            .map(|entry| entry.history_id.as_str())
+            .collect();
+        assert_eq!(ids, ["h3", "h1"]);
+        let next = page.next.unwrap();
+        assert_eq!((next.offset, next.limit), (3, 3));
+
+        let opts = HistoryOptions {
+            user: Some("u2".into()),
+            since: Some("2022-08-01T11:45:00Z".parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(!opts.matches(&page.entries[0]));
+        assert!(opts.matches(&page.entries[1]));
+    }
+
+    #[test]
+    fn moderation_requests() -> anyhow::Result<()> {
+        let ok = || (200, json!({ "data": {} }));
+        let waitlist = || (200, json!({ "data": ["u1", "u2"] }));
+        let server = MockHttp::start(vec![
+            (200, json!({ "meta": { "jwt": "token" } })),
+            waitlist(),
+            waitlist(),
+            waitlist(),
+            (200, json!({ "data": { "locked": true } })),
+            ok(),
+            ok(),
+            ok(),
+            ok(),
+            ok(),
+            ok(),
+            ok(),
+            ok(),
+            (
+                200,
+                json!({ "data": [
+                    { "_id": "u3", "username": "Someone Else", "roles": [] },
+                    { "_id": "u2", "username": "Someone", "roles": ["user"] },
+                ] }),
+            ),
+            ok(),
+            ok(),
+            (200, json!({ "data": { "motd": "Welcome!" } })),
+            ok(),
+            ok(),
+        ]);
+        let api = login(&server);
+
+        assert_eq!(api.waitlist_add("u2")?, ["u1", "u2"]);
+        api.waitlist_move("u2", 0)?;
+        api.waitlist_remove("u2")?;
+        assert!(api.waitlist_lock(true)?);
+        api.waitlist_clear()?;
+        api.delete_chat_message("m1")?;
+        api.delete_chat_by_user("u2")?;
+        api.delete_all_chat()?;
+        api.mute("u2", Duration::from_secs(600))?;
+        api.unmute("u2")?;
+        api.ban(BanOptions {
+            user_id: "u2".into(),
+            duration: Some(Duration::from_secs(60)),
+            reason: Some("spam".into()),
+        })?;
+        api.unban("u2")?;
+        assert_eq!(api.user_by_name("someone")?.unwrap().id, "u2");
+        api.add_role("u2", "moderator")?;
+        api.remove_role("u2", "moderator")?;
+        assert_eq!(api.motd()?.as_deref(), Some("Welcome!"));
+        api.set_motd(None)?;
+        api.leave_booth(LeaveBoothOptions {
+            user_id: Some("u2".into()),
+            auto_leave: true,
+        })?;
+
+        let requests = server.requests();
+        let summary: Vec<_> = requests
+            .iter()
+            .map(|request| format!("{} {}", request.method, request.path))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                "POST /auth/login",
+                "POST /waitlist",
+                "PUT /waitlist/move",
+                "DELETE /waitlist/u2",
+                "PUT /waitlist/lock",
+                "DELETE /waitlist",
+                "DELETE /chat/m1",
+                "DELETE /chat/user/u2",
+                "DELETE /chat",
+                "POST /users/u2/mute",
+                "DELETE /users/u2/mute",
+                "POST /bans",
+                "DELETE /bans/u2",
+                "GET /users?filter=someone",
+                "PUT /users/u2/roles/moderator",
+                "DELETE /users/u2/roles/moderator",
+                "GET /motd",
+                "PUT /motd",
+                "PUT /booth/leave",
+            ]
+        );
+        assert!(requests[1..]
+            .iter()
+            .all(|request| request.authorization.as_deref() == Some("JWT token")));
+        assert_eq!(requests[2].body, json!({ "userID": "u2", "position": 0 }));
+        assert_eq!(requests[9].body, json!({ "time": 600 }));
+        assert_eq!(
+            requests[11].body,
+            json!({ "userID": "u2", "duration": 60_000, "permanent": false, "reason": "spam" })
+        );
+        assert_eq!(requests[17].body, json!({ "motd": null }));
+        assert_eq!(
+            requests[18].body,
+            json!({ "userID": "u2", "autoLeave": true })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn request_errors() {
+        let server = MockHttp::start(vec![
+            (200, json!({ "meta": { "jwt": "old" } })),
+            (401, json!({ "errors": [{ "title": "Expired" }] })),
+            (200, json!({ "meta": { "jwt": "new" } })),
+            (200, json!({ "data": [] })),
+            (
+                403,
+                json!({ "errors": [{ "title": "You need the acl.update permission" }] }),
+            ),
+        ]);
+        let api = login(&server);
+
+        // Expired sessions are renewed.
+        assert_eq!(api.waitlist_remove("u2").unwrap(), Vec::<String>::new());
+        let err = api.add_role("u2", "admin").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>(),
+            Some(ApiError::Forbidden(message)) if message == "You need the acl.update permission"
+        ));
+
+        let requests = server.requests();
+        assert_eq!(requests[1].authorization.as_deref(), Some("JWT old"));
+        assert_eq!(requests[3].authorization.as_deref(), Some("JWT new"));
+    }
+
+    #[test]
+    fn classify_status() {
+        assert!(matches!(
+            ApiError::from_status(403, "Forbidden".into(), None),
+            ApiError::Forbidden(_)
+        ));
+        assert!(matches!(
+            ApiError::from_status(404, "Not Found".into(), None),
+            ApiError::NotFound(_)
+        ));
+        assert!(matches!(
+            ApiError::from_status(429, "Too Many Requests".into(), Some("30")),
+            ApiError::RateLimited { retry_after: Some(duration) } if duration == Duration::from_secs(30)
+        ));
+        assert!(matches!(
+            ApiError::from_status(502, "Bad Gateway".into(), None),
+            ApiError::ServerError { status: 502, .. }
+        ));
+        assert!(matches!(
+            ApiError::from_status(418, "I'm a teapot".into(), None),
+            ApiError::Status { status: 418, .. }
+        ));
+    }
+}
@@ -0,0 +1,366 @@
+use crate::handler::{ChatCommand, ChatMessage, Handler};
+use std::fmt::Write as _;
+
+/// How many values an argument accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arity {
+    Required,
+    Optional,
+    /// Zero or more of the remaining arguments.
+    Rest,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Argument {
+    pub name: &'static str,
+    pub arity: Arity,
+}
+
+impl Argument {
+    pub const fn required(name: &'static str) -> Self {
+        Self {
+            name,
+            arity: Arity::Required,
+        }
+    }
+
+    pub const fn optional(name: &'static str) -> Self {
+        Self {
+            name,
+            arity: Arity::Optional,
+        }
+    }
+
+    pub const fn rest(name: &'static str) -> Self {
+        Self {
+            name,
+            arity: Arity::Rest,
+        }
+    }
+}
+
+/// A chat command that a handler responds to.
+#[derive(Debug, Clone, Copy)]
+pub struct Command {
+    /// The name of the command without the command prefix. Subcommands are separated by a space, eg.
+    /// `skiplist add`.
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub arguments: &'static [Argument],
+    pub description: &'static str,
+    /// The role a user needs to use this command.
+    pub role: Option<&'static str>,
+}
+
+impl Command {
+    pub const fn new(name: &'static str, description: &'static str) -> Self {
+        Self {
+            name,
+            aliases: &[],
+            arguments: &[],
+            description,
+            role: None,
+        }
+    }
+
+    pub const fn aliases(self, aliases: &'static [&'static str]) -> Self {
+        Self { aliases, ..self }
+    }
+
+    pub const fn arguments(self, arguments: &'static [Argument]) -> Self {
+        Self { arguments, ..self }
+    }
+
+    pub const fn role(self, role: &'static str) -> Self {
+        Self {
+            role: Some(role),
+            ..self
+        }
+    }
+
+    pub fn usage(&self, prefix: &str) -> String {
+        let mut usage = format!("{prefix}{}", self.name);
+        for argument in self.arguments {
+            let _ = match argument.arity {
+                Arity::Required => write!(usage, " <{}>", argument.name),
+                Arity::Optional => write!(usage, " [{}]", argument.name),
+                Arity::Rest => write!(usage, " [{}...]", argument.name),
+            };
+        }
+        usage
+    }
+
+    /// Check if the command can be called with this many arguments.
+    fn accepts(&self, count: usize) -> bool {
+        let required = self
+            .arguments
+            .iter()
+            .filter(|argument| argument.arity == Arity::Required)
+            .count();
+        let unbounded = self
+            .arguments
+            .iter()
+            .any(|argument| argument.arity == Arity::Rest);
+
+        count >= required && (unbounded || count <= self.arguments.len())
+    }
+
+    /// Check if a chat command calls this command, returning the number of words used by the name.
+    fn matches(&self, chat: &ChatCommand) -> Option<usize> {
+        std::iter::once(&self.name)
+            .chain(self.aliases)
+            .filter_map(|name| {
+                let mut words = name.split(' ');
+                if words.next() != Some(chat.command.as_str()) {
+                    return None;
+                }
+
+                let mut arguments = chat.arguments.iter();
+                let mut length = 1;
+                for word in words {
+                    if arguments.next().map(String::as_str) != Some(word) {
+                        return None;
+                    }
+                    length += 1;
+                }
+                Some(length)
+            })
+            .max()
+    }
+}
+
+/// A command called from chat, after the arguments were checked against the command definition.
+#[derive(Debug)]
+pub struct Invocation<'a> {
+    pub command: &'static Command,
+    /// The arguments, excluding any subcommand names.
+    pub arguments: &'a [String],
+    pub message: &'a ChatMessage,
+    /// The command prefix, for referring to other commands in responses.
+    pub prefix: &'a str,
+}
+
+impl Invocation<'_> {
+    pub fn usage(&self) -> String {
+        self.command.usage(self.prefix)
+    }
+}
+
+const HELP: Command = Command::new(
+    "help",
+    "List the available commands, or show how to use a command.",
+)
+.arguments(&[Argument::rest("command")]);
+
+/// What to do in response to a chat command.
+#[derive(Debug)]
+pub enum Dispatch<'a> {
+    /// Call the handler at this index.
+    Handler(usize, Invocation<'a>),
+    /// Send this response to chat.
+    Reply(String),
+}
+
+/// The commands registered by all handlers.
+#[derive(Debug)]
+pub struct Commands {
+    entries: Vec<(&'static Command, Option<usize>)>,
+    prefix: String,
+}
+
+impl Commands {
+    pub fn new(handlers: &[Box<dyn Handler + Send>], prefix: &str) -> Self {
+        let mut entries = vec![(&HELP, None)];
+        for (index, handler) in handlers.iter().enumerate() {
+            entries.extend(
+                handler
+                    .commands()
+                    .iter()
+                    .map(|command| (command, Some(index))),
+            );
+        }
+        Self {
+            entries,
+            prefix: prefix.to_string(),
+        }
+    }
+
+    pub fn set_prefix(&mut self, prefix: &str) {
+        self.prefix = prefix.to_string();
+    }
+
+    /// Find the command called by a chat message. Returns `None` for unknown commands.
+    pub fn dispatch<'a>(&'a self, message: &'a ChatMessage) -> Option<Dispatch<'a>> {
+        let chat = message.command()?;
+        let (command, handler, length) = self
+            .entries
+            .iter()
+            .filter_map(|&(command, handler)| Some((command, handler, command.matches(chat)?)))
+            .max_by_key(|&(_, _, length)| length)?;
+
+        let arguments = &chat.arguments[length - 1..];
+        if !command.accepts(arguments.len()) {
+            return Some(Dispatch::Reply(format!(
+                "usage: {}",
+                command.usage(&self.prefix)
+            )));
+        }
+
+        Some(match handler {
+            Some(index) => Dispatch::Handler(
+                index,
+                Invocation {
+                    command,
+                    arguments,
+                    message,
+                    prefix: &self.prefix,
+                },
+            ),
+            None => Dispatch::Reply(self.help(arguments)),
+        })
+    }
+
+    fn help(&self, arguments: &[String]) -> String {
+        let prefix = &self.prefix;
+        if arguments.is_empty() {
+            let mut names = vec![];
+            for (command, _) in &self.entries {
+                let name = command.name.split(' ').next().unwrap();
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+            let names = names
+                .iter()
+                .map(|name| format!("{prefix}{name}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            return format!("Commands: {names}. Use {prefix}help <command> for details.");
+        }
+
+        let name = arguments.join(" ");
+        let name = name.strip_prefix(prefix.as_str()).unwrap_or(&name);
+        let lines: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(command, _)| command)
+            .filter(|command| command.name == name || command.name.starts_with(&format!("{name} ")))
+            .map(|command| {
+                let mut line = format!("{}: {}", command.usage(prefix), command.description);
+                if !command.aliases.is_empty() {
+                    let aliases = command
+                        .aliases
+                        .iter()
+                        .map(|alias| format!("{prefix}{alias}"))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    let _ = write!(line, " (also {aliases})");
+                }
+                if let Some(role) = command.role {
+                    let _ = write!(line, " Requires the {role} role.");
+                }
+                line
+            })
+            .collect();
+
+        if lines.is_empty() {
+            format!("Unknown command {prefix}{name}.")
+        } else {
+            lines.join(" | ")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Argument, Command, Commands, Dispatch, Invocation};
+    use crate::handler::{Api, ChatMessage, Handler};
+    use anyhow::Result;
+
+    #[derive(Debug)]
+    struct Test;
+    impl Handler for Test {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        fn commands(&self) -> &'static [Command] {
+            const COMMANDS: &[Command] = &[
+                Command::new("skiplist", "Add a song to the autoskip list.")
+                    .arguments(&[Argument::optional("media"), Argument::required("reason")]),
+                Command::new("skiplist skip", "Add the current song and skip it.")
+                    .aliases(&["blacklist skip"])
+                    .arguments(&[Argument::required("reason")])
+                    .role("moderator"),
+            ];
+            COMMANDS
+        }
+
+        fn handle_command(&mut self, _api: Api, _invocation: &Invocation) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn dispatch() {
+        let handlers: Vec<Box<dyn Handler + Send>> = vec![Box::new(Test)];
+        let commands = Commands::new(&handlers, "!");
+
+        let message = ChatMessage::test("1", "!skiplist skip \"history\"");
+        let Some(Dispatch::Handler(0, invocation)) = commands.dispatch(&message) else {
+            panic!("expected skiplist skip to be dispatched")
+        };
+        assert_eq!(invocation.command.name, "skiplist skip");
+        assert_eq!(invocation.arguments, ["history"]);
+
+        let message = ChatMessage::test("1", "!blacklist skip history");
+        let Some(Dispatch::Handler(0, invocation)) = commands.dispatch(&message) else {
+            panic!("expected alias to be dispatched")
+        };
+        assert_eq!(invocation.command.name, "skiplist skip");
+
+        let message = ChatMessage::test("1", "!skiplist youtube:abc history");
+        let Some(Dispatch::Handler(0, invocation)) = commands.dispatch(&message) else {
+            panic!("expected skiplist to be dispatched")
+        };
+        assert_eq!(invocation.command.name, "skiplist");
+        assert_eq!(invocation.arguments, ["youtube:abc", "history"]);
+
+        let message = ChatMessage::test("1", "!skiplist");
+        let Some(Dispatch::Reply(reply)) = commands.dispatch(&message) else {
+            panic!("expected usage error")
+        };
+        assert_eq!(reply, "usage: !skiplist [media] <reason>");
+
+        assert!(commands
+            .dispatch(&ChatMessage::test("1", "!unknown"))
+            .is_none());
+        assert!(commands
+            .dispatch(&ChatMessage::test("1", "not a command"))
+            .is_none());
+    }
+
+    #[test]
+    fn help() {
+        let handlers: Vec<Box<dyn Handler + Send>> = vec![Box::new(Test)];
+        let commands = Commands::new(&handlers, "!");
+
+        let message = ChatMessage::test("1", "!help");
+        let Some(Dispatch::Reply(reply)) = commands.dispatch(&message) else {
+            panic!("expected help")
+        };
+        assert_eq!(
+            reply,
+            "Commands: !help, !skiplist. Use !help <command> for details."
+        );
+
+        let message = ChatMessage::test("1", "!help skiplist skip");
+        let Some(Dispatch::Reply(reply)) = commands.dispatch(&message) else {
+            panic!("expected help")
+        };
+        assert_eq!(
+            reply,
+            "!skiplist skip <reason>: Add the current song and skip it. (also !blacklist skip) Requires the moderator role."
+        );
+    }
+}
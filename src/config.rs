@@ -0,0 +1,364 @@
+//! The bot configuration file.
+
+use crate::handlers::{EmoteConfig, HistoryPolicy};
+use crate::outbox::ChatConfig;
+use crate::publisher::Publisher;
+use crate::workers::WorkerConfig;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// The names of the handlers that can be enabled.
+pub const HANDLERS: &[&str] = &[
+    "emotes",
+    "exit",
+    "skiplist",
+    "historyskip",
+    "karma",
+    "version",
+];
+
+/// Handlers that publish pages, and so need a working publisher.
+const PUBLISHING_HANDLERS: &[&str] = &["emotes", "karma"];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// HTTP API endpoint of the üWave server to connect to.
+    pub api_url: String,
+    /// WebSocket API endpoint of the üWave server to connect to.
+    pub socket_url: String,
+    /// Email address of the bot's üWave account. Overridden by `SEKSHIBOT_EMAIL`.
+    pub email: String,
+    /// Password of the bot's üWave account. Overridden by `SEKSHIBOT_PASSWORD`.
+    pub password: String,
+    /// Path to the SQLite database.
+    pub database: String,
+    /// The prefix that chat commands start with.
+    pub command_prefix: String,
+    /// One of `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    pub log_level: String,
+    /// The handlers to enable, see [`HANDLERS`].
+    pub handlers: Vec<String>,
+    pub publisher: Publisher,
+    pub workers: WorkerConfig,
+    pub chat: ChatConfig,
+    pub emotes: EmoteConfig,
+    pub historyskip: HistoryPolicy,
+    /// The file this configuration was loaded from, if any.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            api_url: String::new(),
+            socket_url: String::new(),
+            email: String::new(),
+            password: String::new(),
+            database: "sekshi.sqlite".to_string(),
+            command_prefix: "!".to_string(),
+            log_level: "info".to_string(),
+            handlers: HANDLERS.iter().map(ToString::to_string).collect(),
+            publisher: Publisher::default(),
+            workers: WorkerConfig::default(),
+            chat: ChatConfig::default(),
+            emotes: EmoteConfig::default(),
+            historyskip: HistoryPolicy::default(),
+            path: None,
+        }
+    }
+}
+
+impl Config {
+    /// Read a configuration file, or use the defaults if no path is given. Secrets from the
+    /// environment are applied on top.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let mut config = match path {
+            Some(path) => {
+                let source = std::fs::read_to_string(path)
+                    .with_context(|| format!("could not read {}", path.display()))?;
+                toml::from_str(&source)
+                    .with_context(|| format!("could not parse {}", path.display()))?
+            }
+            None => Self::default(),
+        };
+        config.path = path.map(Path::to_path_buf);
+        config.apply_secrets(|name| std::env::var(name).ok());
+        Ok(config)
+    }
+
+    /// Read the configuration file again. Settings that only take effect after a restart keep
+    /// their current values, and a warning is logged if they were changed.
+    pub fn reload(&self) -> Result<Self> {
+        let Some(path) = &self.path else {
+            bail!("no configuration file was given");
+        };
+        let mut config = Self::load(Some(path))?;
+
+        let changed = self.restart_required(&config);
+        if !changed.is_empty() {
+            log::warn!("restart the bot to apply changes to {}", changed.join(", "));
+        }
+        config.api_url = self.api_url.clone();
+        config.socket_url = self.socket_url.clone();
+        config.email = self.email.clone();
+        config.password = self.password.clone();
+        config.database = self.database.clone();
+        config.handlers = self.handlers.clone();
+        config.workers = self.workers.clone();
+
+        config.validate()?;
+        for warning in config.warnings() {
+            log::warn!("{warning}");
+        }
+        Ok(config)
+    }
+
+    /// Settings in `other` that differ from this configuration, but can not be changed while the
+    /// bot is running. The URLs are not compared, because the command line can override them.
+    fn restart_required(&self, other: &Self) -> Vec<&'static str> {
+        let mut changed = vec![];
+        if self.email != other.email || self.password != other.password {
+            changed.push("email and password");
+        }
+        if self.database != other.database {
+            changed.push("database");
+        }
+        if self.handlers != other.handlers {
+            changed.push("handlers");
+        }
+        if self.workers != other.workers {
+            changed.push("workers");
+        }
+        changed
+    }
+
+    /// Override secrets with the values of environment variables, if they are set.
+    fn apply_secrets(&mut self, var: impl Fn(&str) -> Option<String>) {
+        if let Some(email) = var("SEKSHIBOT_EMAIL") {
+            self.email = email;
+        }
+        if let Some(password) = var("SEKSHIBOT_PASSWORD") {
+            self.password = password;
+        }
+        if let Publisher::Neocities { username, password } = &mut self.publisher {
+            if let Some(value) = var("NEOCITIES_USERNAME") {
+                *username = value;
+            }
+            if let Some(value) = var("NEOCITIES_PASSWORD") {
+                *password = value;
+            }
+        }
+    }
+
+    pub fn log_level(&self) -> Result<log::LevelFilter> {
+        self.log_level
+            .parse()
+            .with_context(|| format!("invalid log level {:?}", self.log_level))
+    }
+
+    pub fn handler_enabled(&self, name: &str) -> bool {
+        self.handlers.iter().any(|handler| handler == name)
+    }
+
+    /// Find everything that would stop the bot from working with this configuration.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        for (name, value, schemes) in [
+            ("api_url", &self.api_url, &["http", "https"]),
+            ("socket_url", &self.socket_url, &["ws", "wss"]),
+        ] {
+            if value.is_empty() {
+                problems.push(format!("{name} is not set"));
+                continue;
+            }
+            match url::Url::parse(value) {
+                Ok(url) if schemes.contains(&url.scheme()) => (),
+                Ok(url) => problems.push(format!(
+                    "{name} must be a {} URL, not {}",
+                    schemes.join(" or "),
+                    url.scheme()
+                )),
+                Err(err) => problems.push(format!("{name} is not a valid URL: {err}")),
+            }
+        }
+
+        if self.email.is_empty() {
+            problems.push("email is not set, use SEKSHIBOT_EMAIL".to_string());
+        }
+        if self.password.is_empty() {
+            problems.push("password is not set, use SEKSHIBOT_PASSWORD".to_string());
+        }
+        if self.database.is_empty() {
+            problems.push("database is not set".to_string());
+        }
+        if self.command_prefix.is_empty() || self.command_prefix.contains(char::is_whitespace) {
+            problems.push("command_prefix must be non-empty and contain no spaces".to_string());
+        }
+        if let Err(err) = self.log_level() {
+            problems.push(err.to_string());
+        }
+
+        if self.workers.threads == 0 {
+            problems.push("workers.threads must be at least 1".to_string());
+        }
+        if self.workers.timeout < self.workers.slow {
+            problems.push("workers.timeout must not be shorter than workers.slow".to_string());
+        }
+
+        if self.chat.max_length == 0 || self.chat.messages == 0 {
+            problems.push("chat.max_length and chat.messages must be at least 1".to_string());
+        }
+
+        if self.emotes.inline && self.emotes.inline_limit == 0 {
+            problems.push("emotes.inline_limit must be at least 1".to_string());
+        }
+
+        for handler in &self.handlers {
+            if !HANDLERS.contains(&handler.as_str()) {
+                problems.push(format!(
+                    "unknown handler {handler:?}, expected one of {}",
+                    HANDLERS.join(", ")
+                ));
+            }
+        }
+
+        if let Publisher::Directory { path, .. } = &self.publisher {
+            if self.publishes() && !path.is_dir() {
+                problems.push(format!(
+                    "publisher path {} is not a directory",
+                    path.display()
+                ));
+            }
+        }
+
+        problems
+    }
+
+    fn publishes(&self) -> bool {
+        PUBLISHING_HANDLERS
+            .iter()
+            .any(|name| self.handler_enabled(name))
+    }
+
+    /// Find settings that make some commands fail, but do not stop the bot from working.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        if let Publisher::Neocities { username, password } = &self.publisher {
+            if self.publishes() && (username.is_empty() || password.is_empty()) {
+                warnings.push(
+                    "neocities publisher needs NEOCITIES_USERNAME and NEOCITIES_PASSWORD, pages can not be published"
+                        .to_string(),
+                );
+            }
+        }
+        warnings
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let problems = self.problems();
+        if !problems.is_empty() {
+            bail!("invalid configuration: {}", problems.join("; "));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use crate::handlers::FuzzyMode;
+    use crate::publisher::Publisher;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            api_url = "https://wlk.yt/api"
+            socket_url = "wss://wlk.yt/api/socket"
+            email = "bot@wlk.yt"
+            command_prefix = "."
+            handlers = ["emotes", "historyskip"]
+
+            [publisher]
+            type = "neocities"
+            username = "sekshibot"
+
+            [historyskip]
+            window = "2h"
+            fuzzy = "warn"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.database, "sekshi.sqlite");
+        assert_eq!(config.historyskip.window, Duration::from_secs(2 * 60 * 60));
+        assert_eq!(config.historyskip.fuzzy, FuzzyMode::Warn);
+        assert!(config.handler_enabled("emotes"));
+        assert!(!config.handler_enabled("karma"));
+        assert_eq!(
+            config.problems(),
+            ["password is not set, use SEKSHIBOT_PASSWORD"]
+        );
+        assert_eq!(config.warnings().len(), 1);
+
+        config.apply_secrets(|name| match name {
+            "SEKSHIBOT_PASSWORD" => Some("hunter2".to_string()),
+            "NEOCITIES_PASSWORD" => Some("hunter3".to_string()),
+            _ => None,
+        });
+        assert_eq!(config.email, "bot@wlk.yt");
+        let Publisher::Neocities { username, password } = &config.publisher else {
+            panic!("expected neocities publisher")
+        };
+        assert_eq!(
+            (username.as_str(), password.as_str()),
+            ("sekshibot", "hunter3")
+        );
+        assert!(config.validate().is_ok());
+        assert!(config.warnings().is_empty());
+    }
+
+    #[test]
+    fn example_config() {
+        let config: Config = toml::from_str(include_str!("../sekshibot.example.toml")).unwrap();
+        assert_eq!(config.handlers, Config::default().handlers);
+    }
+
+    #[test]
+    fn restart_required() {
+        let config = Config::default();
+        let mut reloaded = config.clone();
+        reloaded.command_prefix = ".".to_string();
+        reloaded.chat.messages = 2;
+        assert!(config.restart_required(&reloaded).is_empty());
+
+        reloaded.database = "other.sqlite".to_string();
+        reloaded.workers.threads = 8;
+        assert_eq!(config.restart_required(&reloaded), ["database", "workers"]);
+    }
+
+    #[test]
+    fn invalid_config() {
+        assert!(toml::from_str::<Config>("unknown_setting = 1").is_err());
+        assert!(toml::from_str::<Config>("[publisher]\ntype = \"ftp\"").is_err());
+        assert!(toml::from_str::<Config>("[historyskip]\nwindw = \"2h\"").is_err());
+
+        let config: Config = toml::from_str(
+            r#"
+            api_url = "wss://wlk.yt/api"
+            socket_url = "not a url"
+            email = "bot@wlk.yt"
+            password = "hunter2"
+            command_prefix = ""
+            log_level = "loud"
+            handlers = ["version", "dance"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.problems().len(), 5);
+    }
+}
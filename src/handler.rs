@@ -1,14 +1,22 @@
-use crate::api::uwave::{BaseMedia, HttpApi, MediaWithOverrides};
-use anyhow::{bail, Error, Result};
+use crate::api::uwave::{BaseMedia, HttpApi, MediaWithOverrides, NowState, User};
+use crate::command::{Command, Invocation};
+use crate::config::Config;
+use crate::outbox::ChatConfig;
+use crate::publisher::Publisher;
+use crate::roles::{includes_role, RoleCache};
+use anyhow::{bail, Result};
 use flume::Sender;
+use polling::Poller;
 use r2d2::PooledConnection;
 use r2d2_sqlite::SqliteConnectionManager;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use std::fmt::Display;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
 
-fn parse_message(input: &str) -> Result<(&str, Vec<&str>)> {
+fn parse_message<'a>(input: &'a str, prefix: &str) -> Result<(&'a str, Vec<&'a str>)> {
     use nom::branch::alt;
-    use nom::bytes::complete::{escaped, is_not, take_while};
+    use nom::bytes::complete::{escaped, is_not, tag, take_while};
     use nom::character::complete::{alpha1, char, space0, space1};
     use nom::combinator::{all_consuming, opt};
     use nom::error::Error;
@@ -16,8 +24,8 @@ fn parse_message(input: &str) -> Result<(&str, Vec<&str>)> {
     use nom::sequence::{preceded, terminated, tuple};
     use nom::{Err, IResult};
 
-    fn parser(input: &str) -> IResult<&str, (&str, Vec<&str>)> {
-        let cmd_parser = preceded(char('!'), alpha1);
+    let parser = |input: &'a str| -> IResult<&'a str, (&'a str, Vec<&'a str>)> {
+        let cmd_parser = preceded(tag(prefix), alpha1);
         let string_parser = escaped(is_not("\""), '\\', char('"'));
         let onearg_parser = alt((
             preceded(char('"'), terminated(string_parser, char('"'))),
@@ -28,7 +36,7 @@ fn parse_message(input: &str) -> Result<(&str, Vec<&str>)> {
             tuple((cmd_parser, opt(preceded(space1, args_parser)), space0))(input)?;
 
         Ok((input, (cmd, args.unwrap_or_default())))
-    }
+    };
 
     let mut full_parser = all_consuming(parser);
 
@@ -63,9 +71,25 @@ pub struct ChatMessage {
     command: Option<ChatCommand>,
 }
 
+#[cfg(test)]
+impl ChatMessage {
+    /// A chat message from `user_id`, with commands parsed using the `!` prefix.
+    pub(crate) fn test(user_id: &str, message: &str) -> Self {
+        let mut message = Self {
+            id: "1".to_string(),
+            user_id: user_id.to_string(),
+            message: message.to_string(),
+            command: None,
+        };
+        message.parse("!");
+        message
+    }
+}
+
 impl ChatMessage {
-    fn parse(&mut self) {
-        self.command = self.message.parse().ok();
+    /// Parse the command in the message, if it starts with the command `prefix`.
+    pub(crate) fn parse(&mut self, prefix: &str) {
+        self.command = ChatCommand::parse(&self.message, prefix).ok();
     }
 
     pub fn command(&self) -> Option<&ChatCommand> {
@@ -79,11 +103,9 @@ pub struct ChatCommand {
     pub arguments: Vec<String>,
 }
 
-impl std::str::FromStr for ChatCommand {
-    type Err = Error;
-
-    fn from_str(s: &str) -> Result<Self> {
-        let (command, arguments) = parse_message(s)?;
+impl ChatCommand {
+    fn parse(s: &str, prefix: &str) -> Result<Self> {
+        let (command, arguments) = parse_message(s, prefix)?;
         Ok(Self {
             command: command.to_string(),
             arguments: arguments.into_iter().map(ToOwned::to_owned).collect(),
@@ -91,13 +113,132 @@ impl std::str::FromStr for ChatCommand {
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct SkipMessage {
+    #[serde(rename = "userID")]
+    pub user_id: String,
+    /// Unset if the DJ skipped themselves.
+    #[serde(rename = "moderatorID", default)]
+    pub moderator_id: Option<String>,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// A change to the waitlist. Contains the new waitlist.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WaitlistMessage {
+    #[serde(rename = "userID")]
+    pub user_id: String,
+    /// Set if the change was done by a moderator, rather than the user themselves.
+    #[serde(rename = "moderatorID", default)]
+    pub moderator_id: Option<String>,
+    #[serde(default)]
+    pub position: Option<usize>,
+    pub waitlist: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MuteMessage {
+    #[serde(rename = "userID")]
+    pub user_id: String,
+    #[serde(rename = "moderatorID")]
+    pub moderator_id: String,
+    /// Millisecond timestamp.
+    #[serde(rename = "expiresAt")]
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BanMessage {
+    #[serde(rename = "userID")]
+    pub user_id: String,
+    #[serde(rename = "moderatorID")]
+    pub moderator_id: String,
+    #[serde(default)]
+    pub permanent: bool,
+    /// Millisecond timestamp, unset for permanent bans.
+    #[serde(rename = "expiresAt", default)]
+    pub expires_at: Option<u64>,
+}
+
 #[derive(Debug, Clone)]
 pub enum MessageType {
     Authenticated,
-    Guests { count: i64 },
+    Guests {
+        count: i64,
+    },
     Advance(Box<AdvanceMessage>),
+    /// The booth advanced, but nobody is playing.
+    BoothEmpty,
+    Skip(SkipMessage),
     ChatMessage(ChatMessage),
-    WaitlistUpdate { user_ids: Vec<String> },
+    /// All chat messages were deleted.
+    ChatDelete {
+        moderator_id: String,
+    },
+    ChatDeleteById {
+        moderator_id: String,
+        id: String,
+    },
+    ChatDeleteByUser {
+        moderator_id: String,
+        user_id: String,
+    },
+    ChatMute(MuteMessage),
+    ChatUnmute {
+        moderator_id: String,
+        user_id: String,
+    },
+    Motd {
+        motd: Option<String>,
+    },
+    WaitlistUpdate {
+        user_ids: Vec<String>,
+    },
+    WaitlistJoin(WaitlistMessage),
+    WaitlistLeave(WaitlistMessage),
+    WaitlistAdd(WaitlistMessage),
+    WaitlistRemove(WaitlistMessage),
+    WaitlistMove(WaitlistMessage),
+    WaitlistLock {
+        locked: bool,
+    },
+    WaitlistClear {
+        moderator_id: Option<String>,
+    },
+    /// `value` is 1 for upvotes and -1 for downvotes.
+    Vote {
+        user_id: String,
+        value: i32,
+    },
+    Favorite {
+        user_id: String,
+        history_id: String,
+    },
+    UserJoin(Box<User>),
+    UserLeave {
+        user_id: String,
+    },
+    NameChange {
+        user_id: String,
+        username: String,
+    },
+    Ban(BanMessage),
+    Unban {
+        moderator_id: String,
+        user_id: String,
+    },
+    RolesAdded {
+        user_id: String,
+        roles: Vec<String>,
+    },
+    RolesRemoved {
+        user_id: String,
+        roles: Vec<String>,
+    },
+    /// The socket connection was lost and has been reestablished. Contains the fresh `now` state,
+    /// as events may have been missed in the meantime.
+    Reconnected(Box<NowState>),
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -108,74 +249,269 @@ pub struct Message {
 }
 
 impl Message {
+    /// Deserialize a single property of the message data.
+    fn field<T: DeserializeOwned>(&self, name: &str) -> Option<T> {
+        T::deserialize(self.data.get(name)?).ok()
+    }
+
+    fn data<T: DeserializeOwned>(self) -> Option<T> {
+        serde_json::from_value(self.data).ok()
+    }
+
     pub fn into_message_type(self) -> Option<MessageType> {
-        match self.command.as_str() {
-            "authenticated" => Some(MessageType::Authenticated),
-            "guests" => Some(MessageType::Guests {
+        let message_type = match self.command.as_str() {
+            "authenticated" => MessageType::Authenticated,
+            "guests" => MessageType::Guests {
                 count: self.data.as_i64()?,
-            }),
-            "advance" => Some(MessageType::Advance(
-                serde_json::from_value(self.data).ok()?,
-            )),
-            "chatMessage" => {
-                let mut chat_message: ChatMessage = serde_json::from_value(self.data).ok()?;
-                chat_message.parse();
-                Some(MessageType::ChatMessage(chat_message))
-            }
-            _ => None,
-        }
+            },
+            "advance" if self.data.is_null() => MessageType::BoothEmpty,
+            "advance" => MessageType::Advance(self.data()?),
+            "skip" => MessageType::Skip(self.data()?),
+            "chatMessage" => MessageType::ChatMessage(self.data()?),
+            "chatDelete" => MessageType::ChatDelete {
+                moderator_id: self.field("moderatorID")?,
+            },
+            "chatDeleteByID" => MessageType::ChatDeleteById {
+                moderator_id: self.field("moderatorID")?,
+                id: self.field("_id")?,
+            },
+            "chatDeleteByUser" => MessageType::ChatDeleteByUser {
+                moderator_id: self.field("moderatorID")?,
+                user_id: self.field("userID")?,
+            },
+            "chatMute" => MessageType::ChatMute(self.data()?),
+            "chatUnmute" => MessageType::ChatUnmute {
+                moderator_id: self.field("moderatorID")?,
+                user_id: self.field("userID")?,
+            },
+            "chatMotd" => MessageType::Motd { motd: self.data()? },
+            "waitlistUpdate" => MessageType::WaitlistUpdate {
+                user_ids: self.data()?,
+            },
+            "waitlistJoin" => MessageType::WaitlistJoin(self.data()?),
+            "waitlistLeave" => MessageType::WaitlistLeave(self.data()?),
+            "waitlistAdd" => MessageType::WaitlistAdd(self.data()?),
+            "waitlistRemove" => MessageType::WaitlistRemove(self.data()?),
+            "waitlistMove" => MessageType::WaitlistMove(self.data()?),
+            "waitlistLock" => MessageType::WaitlistLock {
+                locked: self.field("locked")?,
+            },
+            "waitlistClear" => MessageType::WaitlistClear {
+                moderator_id: self.field("moderatorID"),
+            },
+            "vote" => MessageType::Vote {
+                user_id: self.field("_id")?,
+                value: self.field("value")?,
+            },
+            "favorite" => MessageType::Favorite {
+                user_id: self.field("userID")?,
+                history_id: self.field("historyID")?,
+            },
+            "join" => MessageType::UserJoin(self.data()?),
+            "leave" => MessageType::UserLeave {
+                user_id: self.data()?,
+            },
+            "nameChange" => MessageType::NameChange {
+                user_id: self.field("userID")?,
+                username: self.field("username")?,
+            },
+            "ban" => MessageType::Ban(self.data()?),
+            "unban" => MessageType::Unban {
+                moderator_id: self.field("moderatorID")?,
+                user_id: self.field("userID")?,
+            },
+            "acl:allow" => MessageType::RolesAdded {
+                user_id: self.field("userID")?,
+                roles: self.field("roles")?,
+            },
+            "acl:disallow" => MessageType::RolesRemoved {
+                user_id: self.field("userID")?,
+                roles: self.field("roles")?,
+            },
+            _ => return None,
+        };
+
+        Some(message_type)
     }
 }
 
 pub enum ApiMessage {
+    /// Log out and stop. Sent by the handler thread after the workers shut down.
     Exit,
     SendChat(String),
+    /// Change the chat rate limits after the configuration was reloaded.
+    ChatConfig(ChatConfig),
+}
+
+/// Sends messages to the socket thread, waking it up to process them.
+#[derive(Debug, Clone)]
+pub struct ApiSender {
+    sender: Sender<ApiMessage>,
+    /// Asks the handler thread to shut down the workers before the socket thread logs out.
+    exit_sender: Sender<()>,
+    poller: Arc<Poller>,
+}
+
+impl ApiSender {
+    pub fn new(sender: Sender<ApiMessage>, exit_sender: Sender<()>, poller: Arc<Poller>) -> Self {
+        Self {
+            sender,
+            exit_sender,
+            poller,
+        }
+    }
+
+    pub fn exit(&self) {
+        let _ = self.exit_sender.send(());
+    }
+
+    pub fn send(&self, message: ApiMessage) {
+        if self.sender.send(message).is_err() {
+            log::warn!("socket thread has stopped, dropping message");
+            return;
+        }
+        let _ = self.poller.notify();
+    }
 }
 
 #[derive(Clone)]
 pub struct Api {
-    sender: Sender<ApiMessage>,
+    sender: ApiSender,
     pool: r2d2::Pool<SqliteConnectionManager>,
+    state: Arc<RwLock<NowState>>,
+    roles: RoleCache,
     pub http: HttpApi,
+    publisher: Arc<Publisher>,
 }
 impl Api {
     pub fn new(
-        sender: Sender<ApiMessage>,
+        sender: ApiSender,
         pool: r2d2::Pool<SqliteConnectionManager>,
+        state: Arc<RwLock<NowState>>,
+        roles: RoleCache,
         http: HttpApi,
+        publisher: Arc<Publisher>,
     ) -> Self {
-        Self { sender, pool, http }
+        Self {
+            sender,
+            pool,
+            state,
+            roles,
+            http,
+            publisher,
+        }
     }
 
     pub fn connection(&self) -> PooledConnection<SqliteConnectionManager> {
         self.pool.get().unwrap()
     }
 
+    /// The current state of the room. This is updated before handlers receive a message, so it
+    /// already reflects that message.
+    pub fn state(&self) -> RwLockReadGuard<'_, NowState> {
+        self.state.read().unwrap()
+    }
+
+    /// Find the username of a user, asking the server if they are not online.
+    pub fn username(&self, user_id: &str) -> Result<String> {
+        let online = self
+            .state()
+            .find_user(user_id)
+            .map(|user| user.username.clone());
+        match online {
+            Some(username) => Ok(username),
+            None => Ok(self.http.user(user_id)?.username),
+        }
+    }
+
+    /// Check if a user has a role, or a role that includes it.
+    pub fn has_role(&self, user_id: &str, role: &str) -> Result<bool> {
+        // Online users are kept up to date in the room state.
+        let online_roles = self
+            .state()
+            .find_user(user_id)
+            .map(|user| user.roles.clone());
+        let roles = match online_roles {
+            Some(roles) => roles,
+            None => self.roles.roles(&self.http, user_id)?,
+        };
+        Ok(includes_role(&self.state().roles, &roles, role))
+    }
+
     pub fn send_message(&self, message: impl Display) {
-        self.sender
-            .send(ApiMessage::SendChat(message.to_string()))
-            .unwrap();
+        self.sender.send(ApiMessage::SendChat(message.to_string()));
+    }
+
+    /// Publish an HTML page, returning its URL.
+    pub fn publish(&self, page_name: &str, content: &str) -> Result<String> {
+        self.publisher.publish(page_name, content)
     }
 
     pub fn exit(&self) {
-        self.sender.send(ApiMessage::Exit).unwrap();
+        self.sender.exit();
     }
 }
 
 pub trait Handler: std::fmt::Debug {
-    fn handle(&mut self, bot: Api, message: &MessageType) -> Result<()>;
+    /// The name used to enable or disable the handler, in the configuration file and with
+    /// `!module`.
+    fn name(&self) -> &'static str;
+
+    /// The chat commands this handler responds to.
+    fn commands(&self) -> &'static [Command] {
+        &[]
+    }
+
+    /// Handle one of the commands returned by [`Handler::commands`]. The number of arguments has
+    /// already been checked.
+    fn handle_command(&mut self, _bot: Api, _invocation: &Invocation) -> Result<()> {
+        Ok(())
+    }
+
+    /// Handle any socket message.
+    fn handle(&mut self, _bot: Api, _message: &MessageType) -> Result<()> {
+        Ok(())
+    }
+
+    /// Apply settings from a reloaded configuration file.
+    fn reload(&mut self, _bot: Api, _config: &Config) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once when the bot shuts down, after all messages were handled.
+    fn shutdown(&mut self, _bot: Api) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::parse_message;
+    use super::{parse_message, Message, MessageType};
     use anyhow::Result;
 
+    macro_rules! fixture {
+        ($name:literal) => {
+            serde_json::from_str::<Message>(include_str!(concat!(
+                "../fixtures/events/",
+                $name,
+                ".json"
+            )))
+            .unwrap()
+            .into_message_type()
+            .unwrap()
+        };
+    }
+
     #[test]
     fn message_parser() -> Result<()> {
-        assert_eq!(parse_message("!e test")?, ("e", vec!["test"]),);
+        assert_eq!(parse_message("!e test", "!")?, ("e", vec!["test"]),);
+        assert_eq!(parse_message(".e test", ".")?, ("e", vec!["test"]),);
+        assert!(parse_message("!e test", ".").is_err());
         assert_eq!(
-            parse_message("!addemote \"test\" https://wlk.yt/assets/emoji/1f604.png")?,
+            parse_message(
+                "!addemote \"test\" https://wlk.yt/assets/emoji/1f604.png",
+                "!"
+            )?,
             (
                 "addemote",
                 vec!["test", "https://wlk.yt/assets/emoji/1f604.png"]
@@ -183,4 +519,83 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn socket_events() {
+        const USER: &str = "5f1a9b2c8e4d3a0087654321";
+        const MODERATOR: &str = "5d2b7c1a9f0e4b0011223344";
+
+        macro_rules! expect {
+            ($name:literal, $pattern:pat) => {
+                let $pattern = fixture!($name) else {
+                    panic!("unexpected message type for {}", $name)
+                };
+            };
+        }
+
+        expect!("authenticated", MessageType::Authenticated);
+        expect!("guests", MessageType::Guests { count: 4 });
+        expect!("advance", MessageType::Advance(advance));
+        assert_eq!(advance.media.media.source_id, "D1PvIWdJ8xo");
+        expect!("advance-empty", MessageType::BoothEmpty);
+        expect!("skip", MessageType::Skip(skip));
+        assert_eq!(skip.reason.as_deref(), Some("history"));
+        expect!("chatMessage", MessageType::ChatMessage(mut message));
+        message.parse("!");
+        assert_eq!(message.command().unwrap().command, "e");
+        expect!("chatDelete", MessageType::ChatDelete { moderator_id });
+        assert_eq!(moderator_id, MODERATOR);
+        expect!("chatDeleteByID", MessageType::ChatDeleteById { id, .. });
+        assert_eq!(id, "1663688931337-5f1a9b2c");
+        expect!(
+            "chatDeleteByUser",
+            MessageType::ChatDeleteByUser { user_id, .. }
+        );
+        assert_eq!(user_id, USER);
+        expect!("chatMute", MessageType::ChatMute(mute));
+        assert_eq!(mute.expires_at, 1663689231337);
+        expect!("chatUnmute", MessageType::ChatUnmute { user_id, .. });
+        assert_eq!(user_id, USER);
+        expect!("chatMotd", MessageType::Motd { motd: Some(motd) });
+        assert!(motd.starts_with("Welcome"));
+
+        expect!("waitlistUpdate", MessageType::WaitlistUpdate { user_ids });
+        assert_eq!(user_ids.len(), 2);
+        expect!("waitlistJoin", MessageType::WaitlistJoin(join));
+        assert_eq!(join.moderator_id, None);
+        assert_eq!(join.waitlist.len(), 2);
+        expect!("waitlistLeave", MessageType::WaitlistLeave(leave));
+        assert_eq!(leave.waitlist.len(), 1);
+        expect!("waitlistAdd", MessageType::WaitlistAdd(add));
+        assert_eq!(add.position, Some(0));
+        expect!("waitlistRemove", MessageType::WaitlistRemove(remove));
+        assert_eq!(remove.moderator_id.as_deref(), Some(MODERATOR));
+        expect!("waitlistMove", MessageType::WaitlistMove(moved));
+        assert_eq!(moved.position, Some(1));
+        expect!("waitlistLock", MessageType::WaitlistLock { locked: true });
+        expect!(
+            "waitlistClear",
+            MessageType::WaitlistClear {
+                moderator_id: Some(_)
+            }
+        );
+
+        expect!("vote", MessageType::Vote { value: -1, .. });
+        expect!("favorite", MessageType::Favorite { user_id, .. });
+        assert_eq!(user_id, USER);
+        expect!("join", MessageType::UserJoin(user));
+        assert_eq!(user.username, "ReneeTest");
+        expect!("leave", MessageType::UserLeave { user_id });
+        assert_eq!(user_id, USER);
+        expect!("nameChange", MessageType::NameChange { username, .. });
+        assert_eq!(username, "ReneeTest2");
+        expect!("ban", MessageType::Ban(ban));
+        assert!(!ban.permanent);
+        expect!("unban", MessageType::Unban { moderator_id, .. });
+        assert_eq!(moderator_id, MODERATOR);
+        expect!("acl-allow", MessageType::RolesAdded { roles, .. });
+        assert_eq!(roles, ["moderator"]);
+        expect!("acl-disallow", MessageType::RolesRemoved { roles, .. });
+        assert_eq!(roles, ["moderator"]);
+    }
 }
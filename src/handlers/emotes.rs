@@ -1,41 +1,581 @@
-use crate::api::neocities;
-use crate::handler::{Api, ChatCommand, Handler, MessageType};
-use crate::SekshiBot;
-use rusqlite::{Connection, OptionalExtension as _};
+use super::deserialize_duration;
+use super::page::render_page;
+use crate::command::{Argument, Command, Invocation};
+use crate::config::Config;
+use crate::handler::{Api, ChatMessage, Handler, MessageType};
+use crate::similarity::similarity;
+use chrono::Utc;
+use chrono_humanize::{Accuracy, HumanTime, Tense};
+use rusqlite::{params, Connection, OptionalExtension as _};
+use serde::Deserialize;
 use shorten_url::shorten;
+use std::collections::HashMap;
 use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use ureq::Agent;
+
+/// Number of emotes listed by `!topemotes`.
+const TOP_IN_CHAT: usize = 5;
+/// How long to wait for an emote URL to respond.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest file that can be used as an emote, in bytes.
+const MAX_EMOTE_SIZE: u64 = 20 * 1024 * 1024;
+/// Number of emote URLs that `!checkemotes` checks at the same time.
+const CHECK_CONCURRENCY: usize = 8;
+/// Number of names suggested when an emote does not exist.
+const SUGGESTIONS: usize = 3;
+/// How similar a name must be to the requested one to be suggested, from 0 to 1.
+const SUGGESTION_SIMILARITY: f64 = 0.5;
+
+/// Why a URL can not be used as an emote.
+#[derive(Debug, PartialEq, thiserror::Error)]
+enum EmoteUrlError {
+    #[error("{0} is not a valid http(s) URL")]
+    Invalid(String),
+    #[error("the server responded with status {0}")]
+    Status(u16),
+    #[error("{0} is not an image or video")]
+    ContentType(String),
+    #[error("the file is too large ({0} bytes)")]
+    TooLarge(u64),
+    #[error("could not load it: {0}")]
+    Unreachable(String),
+}
+
+impl EmoteUrlError {
+    /// Whether the URL may work later. Emotes with such URLs are still added, with a warning.
+    fn is_temporary(&self) -> bool {
+        matches!(self, Self::Unreachable(_))
+    }
+}
+
+/// Check that a URL points to an image or a video, without downloading it. Files of unknown size
+/// are accepted.
+fn check_emote_url(agent: &Agent, url: &str, max_size: u64) -> Result<(), EmoteUrlError> {
+    match url::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => (),
+        _ => return Err(EmoteUrlError::Invalid(url.to_string())),
+    }
+
+    let response = match agent.head(url).timeout(FETCH_TIMEOUT).call() {
+        // Some servers do not support HEAD requests. Ask for the first byte instead.
+        Err(ureq::Error::Status(405 | 501, _)) => agent
+            .get(url)
+            .set("Range", "bytes=0-0")
+            .timeout(FETCH_TIMEOUT)
+            .call(),
+        result => result,
+    };
+    let response = match response {
+        Ok(response) => response,
+        Err(ureq::Error::Status(status, _)) => return Err(EmoteUrlError::Status(status)),
+        Err(err) => return Err(EmoteUrlError::Unreachable(err.to_string())),
+    };
+
+    let content_type = response.content_type().to_ascii_lowercase();
+    if !content_type.starts_with("image/") && !content_type.starts_with("video/") {
+        return Err(EmoteUrlError::ContentType(content_type));
+    }
+    // A ranged response has the size of the whole file after the slash in Content-Range.
+    let size = response
+        .header("Content-Range")
+        .and_then(|range| range.rsplit_once('/'))
+        .map(|(_, size)| size)
+        .or_else(|| response.header("Content-Length"))
+        .and_then(|size| size.parse().ok());
+    match size {
+        Some(size) if size > max_size => Err(EmoteUrlError::TooLarge(size)),
+        _ => Ok(()),
+    }
+}
+
+/// Check the URLs of emotes, returning the ones that do not work. Returns `None` if `stop` was set
+/// before all emotes were checked.
+fn check_emote_urls(
+    agent: &Agent,
+    emotes: &[(String, String)],
+    stop: &AtomicBool,
+) -> Option<Vec<(String, EmoteUrlError)>> {
+    let mut broken = vec![];
+    for chunk in emotes.chunks(CHECK_CONCURRENCY) {
+        if stop.load(Ordering::Relaxed) {
+            return None;
+        }
+        std::thread::scope(|scope| {
+            let checks: Vec<_> = chunk
+                .iter()
+                .map(|(name, url)| {
+                    scope.spawn(move || (name, check_emote_url(agent, url, MAX_EMOTE_SIZE)))
+                })
+                .collect();
+            for check in checks {
+                if let (name, Err(err)) = check.join().unwrap() {
+                    broken.push((name.clone(), err));
+                }
+            }
+        });
+    }
+    Some(broken)
+}
+
+/// A `!checkemotes` run in the background.
+#[derive(Debug)]
+struct EmoteCheck {
+    thread: JoinHandle<()>,
+    stop: Arc<AtomicBool>,
+}
+
+/// Check a URL before storing it. Returns a warning if the URL could not be checked.
+fn check_new_emote_url(agent: &Agent, url: &str) -> Result<Option<EmoteUrlError>, EmoteUrlError> {
+    match check_emote_url(agent, url, MAX_EMOTE_SIZE) {
+        Ok(()) => Ok(None),
+        Err(err) if err.is_temporary() => Ok(Some(err)),
+        Err(err) => Err(err),
+    }
+}
+
+/// Where an emote came from and how popular it is.
+#[derive(Debug, PartialEq)]
+struct EmoteInfo {
+    name: String,
+    /// User ID of whoever added the emote. Unknown for emotes that were added before this was
+    /// recorded.
+    added_by: Option<String>,
+    /// Unix timestamp in seconds.
+    added_at: Option<i64>,
+    uses: i64,
+}
+
+/// What [`Emotes::delete_emote`] deleted.
+#[derive(Debug, PartialEq)]
+enum Deleted {
+    Emote,
+    Alias { emote: String },
+    Nothing,
+}
+
+/// What [`Emotes::rename_emote`] did.
+#[derive(Debug, PartialEq)]
+enum Renamed {
+    Done,
+    /// The new name is already taken.
+    Exists,
+    Nothing,
+}
+
+/// What [`Emotes::alias_emote`] did.
+#[derive(Debug, PartialEq)]
+enum Aliased {
+    Alias {
+        emote: String,
+    },
+    /// The alias is already taken.
+    Exists,
+    Nothing,
+}
+
+/// Describe an emote for `!emoteinfo`, with the username of whoever added it.
+fn describe_emote(info: &EmoteInfo, added_by: Option<&str>, now: i64) -> String {
+    let mut message = info.name.clone();
+    if let (Some(added_by), Some(added_at)) = (added_by, info.added_at) {
+        let ago = HumanTime::from(chrono::Duration::seconds(added_at - now))
+            .to_text_en(Accuracy::Rough, Tense::Past);
+        message.push_str(&format!(" was added by {added_by} {ago} and"));
+    }
+    match info.uses {
+        1 => message.push_str(" has been used once."),
+        uses => message.push_str(&format!(" has been used {uses} times.")),
+    }
+    message
+}
+
+/// Find `:name:` tokens in a chat message, without duplicates.
+fn inline_emote_names(message: &str) -> Vec<&str> {
+    let mut names = vec![];
+    let mut rest = message;
+    while let Some(start) = rest.find(':') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find(':') else {
+            break;
+        };
+        let name = &rest[..end];
+        // Otherwise, the closing colon may open the next token, like in `12:30 :wave:`.
+        if !name.is_empty() && !name.contains(char::is_whitespace) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+            rest = &rest[end + 1..];
+        }
+    }
+    names
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmoteConfig {
+    /// Post the emotes named like `:name:` in ordinary chat messages.
+    pub inline: bool,
+    /// The most emotes posted for one chat message.
+    pub inline_limit: usize,
+    /// How long a user has to wait until their next inline emotes are posted.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub inline_cooldown: Duration,
+}
+
+impl Default for EmoteConfig {
+    fn default() -> Self {
+        Self {
+            inline: false,
+            inline_limit: 3,
+            inline_cooldown: Duration::from_secs(30),
+        }
+    }
+}
 
 #[derive(Debug)]
-pub struct Emotes;
+pub struct Emotes {
+    config: EmoteConfig,
+    /// When inline emotes were last posted for each user.
+    inline_used_at: HashMap<String, Instant>,
+    /// The last `!checkemotes` run, which may still be going.
+    check: Option<EmoteCheck>,
+}
+
 impl Emotes {
-    pub fn new(_bot: &mut SekshiBot) -> anyhow::Result<Self> {
-        Ok(Self)
+    pub fn new(config: EmoteConfig) -> Self {
+        Self {
+            config,
+            inline_used_at: HashMap::new(),
+            check: None,
+        }
+    }
+
+    /// Find the name of the emote that `name` refers to, following aliases.
+    fn resolve_name(&self, db: &Connection, name: &str) -> anyhow::Result<Option<String>> {
+        let name = db
+            .query_row(
+                "SELECT name FROM emotes
+                WHERE name = COALESCE((SELECT emote FROM emote_aliases WHERE alias = ?1), ?1)",
+                [name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(name)
+    }
+
+    fn get_alias(&self, db: &Connection, alias: &str) -> anyhow::Result<Option<String>> {
+        let emote = db
+            .query_row(
+                "SELECT emote FROM emote_aliases WHERE alias = ?",
+                [alias],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(emote)
     }
 
     fn get_emote(&self, db: &Connection, name: &str) -> anyhow::Result<Option<String>> {
         let url = db
-            .query_row("SELECT url FROM emotes WHERE name = ?", [name], |row| {
-                row.get(0)
-            })
+            .query_row(
+                "SELECT url FROM emotes
+                WHERE name = COALESCE((SELECT emote FROM emote_aliases WHERE alias = ?1), ?1)",
+                [name],
+                |row| row.get(0),
+            )
             .optional()?;
         Ok(url)
     }
 
-    fn insert_emote(&self, db: &Connection, name: &str, url: &str) -> anyhow::Result<()> {
-        log::info!("insert {name} {url}");
-        db.execute("INSERT INTO emotes (name, url) VALUES (?, ?)", [name, url])?;
+    fn all_emotes(&self, db: &Connection) -> anyhow::Result<Vec<(String, String)>> {
+        let mut stmt = db.prepare("SELECT name, url FROM emotes ORDER BY name")?;
+        let query = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(query.collect::<Result<_, _>>()?)
+    }
+
+    /// Like [`Emotes::resolve_name`], but falls back to ignoring case.
+    fn find_name(&self, db: &Connection, name: &str) -> anyhow::Result<Option<String>> {
+        if let Some(name) = self.resolve_name(db, name)? {
+            return Ok(Some(name));
+        }
+        // Names may differ only in case, so pick the same one every time.
+        let name = db.query_row(
+            "SELECT COALESCE(
+                (SELECT name FROM emotes WHERE name = ?1 COLLATE NOCASE ORDER BY name LIMIT 1),
+                (SELECT emote FROM emote_aliases WHERE alias = ?1 COLLATE NOCASE
+                    ORDER BY alias LIMIT 1)
+            )",
+            [name],
+            |row| row.get(0),
+        )?;
+        Ok(name)
+    }
+
+    /// Emote names and aliases that look like `name`, most similar first.
+    fn suggest(&self, db: &Connection, name: &str) -> anyhow::Result<Vec<String>> {
+        let mut stmt =
+            db.prepare("SELECT name FROM emotes UNION SELECT alias FROM emote_aliases")?;
+        let query = stmt.query_map([], |row| row.get::<_, String>(0))?;
+
+        let name = name.to_lowercase();
+        let mut candidates = vec![];
+        for candidate in query {
+            let candidate = candidate?;
+            let score = similarity(&name, &candidate.to_lowercase());
+            if score >= SUGGESTION_SIMILARITY {
+                candidates.push((score, candidate));
+            }
+        }
+        candidates.sort_by(|(a_score, a), (b_score, b)| b_score.total_cmp(a_score).then(a.cmp(b)));
+        Ok(candidates
+            .into_iter()
+            .take(SUGGESTIONS)
+            .map(|(_, candidate)| candidate)
+            .collect())
+    }
+
+    fn record_use(
+        &self,
+        db: &Connection,
+        name: &str,
+        user_id: &str,
+        now: i64,
+    ) -> anyhow::Result<()> {
+        db.execute(
+            "INSERT INTO emote_uses (emote, user_id, used_at) VALUES (?, ?, ?)",
+            params![name, user_id, now],
+        )?;
+        Ok(())
+    }
+
+    /// Look up an emote and count the use. Returns the URL.
+    fn use_emote(
+        &self,
+        db: &Connection,
+        name: &str,
+        user_id: &str,
+        now: i64,
+    ) -> anyhow::Result<Option<String>> {
+        let Some(name) = self.find_name(db, name)? else {
+            return Ok(None);
+        };
+        self.record_use(db, &name, user_id, now)?;
+        self.get_emote(db, &name)
+    }
+
+    /// Pick a random emote and count the use. Returns the name and the URL.
+    fn use_random_emote(
+        &self,
+        db: &Connection,
+        user_id: &str,
+        now: i64,
+    ) -> anyhow::Result<Option<(String, String)>> {
+        let emote: Option<(String, String)> = db
+            .query_row(
+                "SELECT name, url FROM emotes ORDER BY RANDOM() LIMIT 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        if let Some((name, _)) = &emote {
+            self.record_use(db, name, user_id, now)?;
+        }
+        Ok(emote)
+    }
+
+    /// Find the URLs of the `:name:` emotes in a chat message, if the user is not on cooldown.
+    fn expand_inline(
+        &mut self,
+        db: &Connection,
+        message: &ChatMessage,
+        now: Instant,
+    ) -> anyhow::Result<Vec<String>> {
+        let cooldown = self.config.inline_cooldown;
+        self.inline_used_at
+            .retain(|_, used_at| now.saturating_duration_since(*used_at) < cooldown);
+
+        let names = inline_emote_names(&message.message);
+        if names.is_empty() || self.inline_used_at.contains_key(&message.user_id) {
+            return Ok(vec![]);
+        }
+
+        let timestamp = Utc::now().timestamp();
+        let mut urls = vec![];
+        for name in names {
+            if urls.len() >= self.config.inline_limit {
+                break;
+            }
+            if let Some(url) = self.use_emote(db, name, &message.user_id, timestamp)? {
+                urls.push(url);
+            }
+        }
+        if !urls.is_empty() {
+            self.inline_used_at.insert(message.user_id.clone(), now);
+        }
+        Ok(urls)
+    }
+
+    fn send_random_emote(&self, api: &Api, user_id: &str) -> anyhow::Result<()> {
+        let now = Utc::now().timestamp();
+        match self.use_random_emote(&api.connection(), user_id, now)? {
+            Some((name, url)) => api.send_message(format_args!("{name}: {url}")),
+            None => api.send_message("There are no emotes yet."),
+        }
         Ok(())
     }
 
+    fn emote_info(&self, db: &Connection, name: &str) -> anyhow::Result<Option<EmoteInfo>> {
+        let Some(name) = self.resolve_name(db, name)? else {
+            return Ok(None);
+        };
+        let info = db.query_row(
+            "SELECT added_by, added_at, (SELECT COUNT(*) FROM emote_uses WHERE emote = name)
+            FROM emotes WHERE name = ?",
+            [&name],
+            |row| {
+                Ok(EmoteInfo {
+                    name: name.clone(),
+                    added_by: row.get(0)?,
+                    added_at: row.get(1)?,
+                    uses: row.get(2)?,
+                })
+            },
+        )?;
+        Ok(Some(info))
+    }
+
+    /// The most used emotes, with their number of uses.
+    fn top(&self, db: &Connection, limit: usize) -> anyhow::Result<Vec<(String, i64)>> {
+        let mut stmt = db.prepare(
+            "SELECT emote, COUNT(*) AS uses FROM emote_uses
+            GROUP BY emote ORDER BY uses DESC, emote LIMIT ?",
+        )?;
+        let query = stmt.query_map([limit], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(query.collect::<Result<_, _>>()?)
+    }
+
+    /// Returns false if the name is already used by an emote or an alias.
+    fn insert_emote(
+        &self,
+        db: &Connection,
+        name: &str,
+        url: &str,
+        added_by: &str,
+        added_at: i64,
+    ) -> anyhow::Result<bool> {
+        if self.resolve_name(db, name)?.is_some() {
+            return Ok(false);
+        }
+        log::info!("insert {name} {url}");
+        db.execute(
+            "INSERT INTO emotes (name, url, added_by, added_at) VALUES (?, ?, ?, ?)",
+            params![name, url, added_by, added_at],
+        )?;
+        Ok(true)
+    }
+
+    /// Returns false if the emote does not exist.
+    fn update_emote(&self, db: &Connection, name: &str, url: &str) -> anyhow::Result<bool> {
+        let Some(name) = self.resolve_name(db, name)? else {
+            return Ok(false);
+        };
+        log::info!("update {name} {url}");
+        db.execute("UPDATE emotes SET url = ? WHERE name = ?", [url, &name])?;
+        Ok(true)
+    }
+
+    /// Delete an emote and its aliases, or only the alias if `name` is an alias.
+    fn delete_emote(&self, db: &Connection, name: &str) -> anyhow::Result<Deleted> {
+        if let Some(emote) = self.get_alias(db, name)? {
+            log::info!("delete alias {name}");
+            db.execute("DELETE FROM emote_aliases WHERE alias = ?", [name])?;
+            return Ok(Deleted::Alias { emote });
+        }
+
+        log::info!("delete {name}");
+        let tx = db.unchecked_transaction()?;
+        let count = tx.execute("DELETE FROM emotes WHERE name = ?", [name])?;
+        tx.execute("DELETE FROM emote_aliases WHERE emote = ?", [name])?;
+        tx.execute("DELETE FROM emote_uses WHERE emote = ?", [name])?;
+        tx.commit()?;
+        Ok(if count > 0 {
+            Deleted::Emote
+        } else {
+            Deleted::Nothing
+        })
+    }
+
+    /// Rename an emote or an alias, keeping the aliases of a renamed emote.
+    fn rename_emote(&self, db: &Connection, name: &str, new_name: &str) -> anyhow::Result<Renamed> {
+        if self.resolve_name(db, new_name)?.is_some() {
+            return Ok(Renamed::Exists);
+        }
+
+        log::info!("rename {name} {new_name}");
+        let tx = db.unchecked_transaction()?;
+        let renamed = tx.execute(
+            "UPDATE emote_aliases SET alias = ? WHERE alias = ?",
+            [new_name, name],
+        )? + tx.execute(
+            "UPDATE emotes SET name = ? WHERE name = ?",
+            [new_name, name],
+        )?;
+        if renamed == 0 {
+            return Ok(Renamed::Nothing);
+        }
+        tx.execute(
+            "UPDATE emote_aliases SET emote = ? WHERE emote = ?",
+            [new_name, name],
+        )?;
+        tx.execute(
+            "UPDATE emote_uses SET emote = ? WHERE emote = ?",
+            [new_name, name],
+        )?;
+        tx.commit()?;
+        Ok(Renamed::Done)
+    }
+
+    /// Make `alias` refer to the emote that `target` refers to.
+    fn alias_emote(&self, db: &Connection, alias: &str, target: &str) -> anyhow::Result<Aliased> {
+        if self.resolve_name(db, alias)?.is_some() {
+            return Ok(Aliased::Exists);
+        }
+        let Some(emote) = self.resolve_name(db, target)? else {
+            return Ok(Aliased::Nothing);
+        };
+
+        log::info!("alias {alias} {emote}");
+        db.execute(
+            "INSERT INTO emote_aliases (alias, emote) VALUES (?, ?)",
+            [alias, &emote],
+        )?;
+        Ok(Aliased::Alias { emote })
+    }
+
     fn render_emote_page(&self, db: &Connection) -> anyhow::Result<String> {
-        let mut stmt = db.prepare("SELECT name, url FROM emotes")?;
+        let mut stmt = db.prepare(
+            "SELECT
+                name,
+                url,
+                (SELECT group_concat(alias, ', ') FROM emote_aliases WHERE emote = name),
+                (SELECT COUNT(*) FROM emote_uses WHERE emote = name) AS uses
+            FROM emotes
+            ORDER BY uses DESC, name",
+        )?;
         let query = stmt.query_map([], |row| {
-            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, i64>(3)?,
+            ))
         })?;
 
         let mut trs = String::new();
-        for pair in query {
-            let (name, url) = pair?;
+        for row in query {
+            let (name, url, aliases, uses) = row?;
 
             write!(
                 &mut trs,
@@ -47,11 +587,14 @@ impl Emotes {
                       {truncatedUrl}
                     </a>
                   </td>
+                  <td>{aliases}</td>
+                  <td class="uses">{uses}</td>
                 </tr>
                 "#,
                 id = html_escape::encode_text(&name),
                 url = html_escape::encode_double_quoted_attribute(&url),
-                truncatedUrl = html_escape::encode_text(&shorten(&url, 50))
+                truncatedUrl = html_escape::encode_text(&shorten(&url, 50)),
+                aliases = html_escape::encode_text(&aliases.unwrap_or_default()),
             )?;
         }
 
@@ -60,13 +603,30 @@ impl Emotes {
             <body>
               <table>
                 <thead><tr>
-                  <th>Name</th>
+                  <th class="sort" data-sort="name">Name</th>
                   <th>URL</th>
+                  <th>Aliases</th>
+                  <th class="sort" data-sort="uses">Uses</th>
                 </tr></thead>
                 <tbody>{trs}</tbody>
               </table>
               <script defer>
+                function sortBy (column) {{
+                  var tbody = document.querySelector('tbody')
+                  var rows = Array.prototype.slice.call(tbody.rows)
+                  rows.sort(function (a, b) {{
+                    var x = a.querySelector('.' + column).textContent
+                    var y = b.querySelector('.' + column).textContent
+                    if (column === 'uses') return y - x
+                    return x.localeCompare(y)
+                  }})
+                  rows.forEach(function (row) {{ tbody.appendChild(row) }})
+                }}
                 if (document.body.classList) onclick = function onclick (event) {{
+                  if (event.target.classList.contains('sort')) {{
+                    sortBy(event.target.getAttribute('data-sort'))
+                    return
+                  }}
                   if (!event.target.classList.contains('name')) {{
                     return
                   }}
@@ -81,64 +641,607 @@ impl Emotes {
         "#
         );
 
-        let body = minify_html::minify(
-            body.as_bytes(),
-            &minify_html::Cfg {
-                minify_css: true,
-                minify_js: true,
-                ..Default::default()
-            },
-        );
-
-        let html = html_index::new()
-            .raw_body(std::str::from_utf8(&body)?)
-            .inline_style(r#"
-                body { margin: 1rem 4rem; background: #333; color: #f4f4f4; font-family: sans-serif; }
-                table { border-collapse: collapse; border-spacing: 0; margin: auto; }
-                tbody > tr:nth-child(2n+1) { background-color: #0000001a; }
-                th, td { padding: .5rem 1rem; }
-                th { text-transform: uppercase; }
-                a { text-decoration: none; color: #ffa3d7; }
-                a:hover { text-decoration: underline; }
-            "#);
-
-        Ok(html.build())
+        render_page(&body)
     }
 }
 
 impl Handler for Emotes {
-    fn handle(&mut self, api: Api, message: &MessageType) -> anyhow::Result<()> {
-        let message = match message {
-            MessageType::ChatMessage(message) => message,
-            _ => return Ok(()),
-        };
+    fn name(&self) -> &'static str {
+        "emotes"
+    }
 
-        let ChatCommand { command, arguments } = match message.command() {
-            Some(c) => c,
-            None => return Ok(()),
-        };
-        match command.as_str() {
-            "e" | "emote" => {
-                let emote_name = &arguments[0];
-                if let Some(url) = self.get_emote(&api.connection(), emote_name)? {
+    fn commands(&self) -> &'static [Command] {
+        const COMMANDS: &[Command] = &[
+            Command::new(
+                "e",
+                "Display a reaction gif, or a random one if no name is given.",
+            )
+            .aliases(&["emote"])
+            // Anything after the name is ignored, like it was before commands had usages.
+            .arguments(&[Argument::optional("emote"), Argument::rest("comment")]),
+            Command::new("randomemote", "Display a random reaction gif."),
+            Command::new("addemote", "Add a new reaction gif.")
+                .arguments(&[Argument::required("emote"), Argument::required("url")])
+                .role("moderator"),
+            Command::new("setemote", "Change the URL of a reaction gif.")
+                .arguments(&[Argument::required("emote"), Argument::required("url")])
+                .role("moderator"),
+            Command::new(
+                "checkemotes",
+                "Check that all reaction gifs still load, and list the ones that do not.",
+            )
+            .role("moderator"),
+            Command::new(
+                "delemote",
+                "Delete a reaction gif and its aliases, or delete an alias.",
+            )
+            .aliases(&["removeemote"])
+            .arguments(&[Argument::required("emote")])
+            .role("moderator"),
+            Command::new("renameemote", "Rename a reaction gif or an alias.")
+                .arguments(&[Argument::required("emote"), Argument::required("name")])
+                .role("moderator"),
+            Command::new("aliasemote", "Add another name for a reaction gif.")
+                .arguments(&[Argument::required("alias"), Argument::required("emote")])
+                .role("moderator"),
+            Command::new(
+                "emoteinfo",
+                "Show who added a reaction gif, when, and how often it was used.",
+            )
+            .arguments(&[Argument::required("emote")]),
+            Command::new("topemotes", "List the most used reaction gifs."),
+            Command::new(
+                "emotes",
+                "Send a link to a page with all the reaction gifs.",
+            ),
+        ];
+        COMMANDS
+    }
+
+    fn handle_command(&mut self, api: Api, invocation: &Invocation) -> anyhow::Result<()> {
+        let arguments = invocation.arguments;
+        match invocation.command.name {
+            "e" => {
+                let user_id = &invocation.message.user_id;
+                let Some(emote_name) = arguments.first() else {
+                    return self.send_random_emote(&api, user_id);
+                };
+                let db = api.connection();
+                let now = Utc::now().timestamp();
+                if let Some(url) = self.use_emote(&db, emote_name, user_id, now)? {
                     api.send_message(url);
+                    return Ok(());
+                }
+
+                let suggestions = self.suggest(&db, emote_name)?;
+                if suggestions.is_empty() {
+                    api.send_message(format_args!("There is no emote {emote_name}."));
+                } else {
+                    api.send_message(format_args!(
+                        "There is no emote {emote_name}. Did you mean {}?",
+                        suggestions.join(", ")
+                    ));
                 }
                 Ok(())
             }
+            "randomemote" => self.send_random_emote(&api, &invocation.message.user_id),
             "addemote" => {
                 let emote_name = &arguments[0];
                 let emote_url = &arguments[1];
-                self.insert_emote(&api.connection(), emote_name, emote_url)?;
-                api.send_message(format_args!("{emote_name} added!"));
+                let user_id = &invocation.message.user_id;
+                let already_exists = format!(
+                    "{emote_name} already exists. Use {}setemote to change it.",
+                    invocation.prefix
+                );
+                if self.resolve_name(&api.connection(), emote_name)?.is_some() {
+                    api.send_message(&already_exists);
+                    return Ok(());
+                }
+                let warning = match check_new_emote_url(api.http.agent(), emote_url) {
+                    Ok(warning) => warning,
+                    Err(err) => {
+                        api.send_message(format_args!("Could not add {emote_name}: {err}."));
+                        return Ok(());
+                    }
+                };
+
+                let now = Utc::now().timestamp();
+                if !self.insert_emote(&api.connection(), emote_name, emote_url, user_id, now)? {
+                    api.send_message(already_exists);
+                } else if let Some(warning) = warning {
+                    api.send_message(format_args!("{emote_name} added, but {warning}."));
+                } else {
+                    api.send_message(format_args!("{emote_name} added!"));
+                }
+                Ok(())
+            }
+            "setemote" => {
+                let emote_name = &arguments[0];
+                let emote_url = &arguments[1];
+                if self.resolve_name(&api.connection(), emote_name)?.is_none() {
+                    api.send_message(format_args!("There is no emote {emote_name}."));
+                    return Ok(());
+                }
+                let warning = match check_new_emote_url(api.http.agent(), emote_url) {
+                    Ok(warning) => warning,
+                    Err(err) => {
+                        api.send_message(format_args!("Could not update {emote_name}: {err}."));
+                        return Ok(());
+                    }
+                };
+
+                if !self.update_emote(&api.connection(), emote_name, emote_url)? {
+                    api.send_message(format_args!("There is no emote {emote_name}."));
+                } else if let Some(warning) = warning {
+                    api.send_message(format_args!("{emote_name} updated, but {warning}."));
+                } else {
+                    api.send_message(format_args!("{emote_name} updated!"));
+                }
+                Ok(())
+            }
+            "checkemotes" => {
+                if let Some(check) = &self.check {
+                    if !check.thread.is_finished() {
+                        api.send_message("The emotes are already being checked.");
+                        return Ok(());
+                    }
+                }
+                let emotes = self.all_emotes(&api.connection())?;
+                // Checking can take a while, so it does not hold up the other emote commands. It
+                // is stopped and joined in `shutdown`.
+                let stop = Arc::new(AtomicBool::new(false));
+                let thread_stop = Arc::clone(&stop);
+                let thread = std::thread::spawn(move || {
+                    let Some(broken) = check_emote_urls(api.http.agent(), &emotes, &thread_stop)
+                    else {
+                        return;
+                    };
+                    if broken.is_empty() {
+                        api.send_message(format_args!("All {} emotes work.", emotes.len()));
+                    } else {
+                        let list = broken
+                            .iter()
+                            .map(|(name, err)| format!("{name}: {err}"))
+                            .collect::<Vec<_>>()
+                            .join("; ");
+                        api.send_message(format_args!(
+                            "{} of {} emotes do not work. {list}",
+                            broken.len(),
+                            emotes.len()
+                        ));
+                    }
+                });
+                self.check = Some(EmoteCheck { thread, stop });
+                Ok(())
+            }
+            "delemote" => {
+                let emote_name = &arguments[0];
+                match self.delete_emote(&api.connection(), emote_name)? {
+                    Deleted::Emote => api.send_message(format_args!("{emote_name} deleted.")),
+                    Deleted::Alias { emote } => api
+                        .send_message(format_args!("Deleted the alias {emote_name} for {emote}.")),
+                    Deleted::Nothing => {
+                        api.send_message(format_args!("There is no emote {emote_name}."))
+                    }
+                }
+                Ok(())
+            }
+            "renameemote" => {
+                let emote_name = &arguments[0];
+                let new_name = &arguments[1];
+                match self.rename_emote(&api.connection(), emote_name, new_name)? {
+                    Renamed::Done => {
+                        api.send_message(format_args!("Renamed {emote_name} to {new_name}."))
+                    }
+                    Renamed::Exists => api.send_message(format_args!("{new_name} already exists.")),
+                    Renamed::Nothing => {
+                        api.send_message(format_args!("There is no emote {emote_name}."))
+                    }
+                }
+                Ok(())
+            }
+            "aliasemote" => {
+                let alias = &arguments[0];
+                let target = &arguments[1];
+                match self.alias_emote(&api.connection(), alias, target)? {
+                    Aliased::Alias { emote } => {
+                        api.send_message(format_args!("{alias} now also shows {emote}."))
+                    }
+                    Aliased::Exists => api.send_message(format_args!("{alias} already exists.")),
+                    Aliased::Nothing => {
+                        api.send_message(format_args!("There is no emote {target}."))
+                    }
+                }
+                Ok(())
+            }
+            "emoteinfo" => {
+                let emote_name = &arguments[0];
+                match self.emote_info(&api.connection(), emote_name)? {
+                    Some(info) => {
+                        let added_by = match &info.added_by {
+                            Some(user_id) => Some(api.username(user_id)?),
+                            None => None,
+                        };
+                        let message =
+                            describe_emote(&info, added_by.as_deref(), Utc::now().timestamp());
+                        api.send_message(message);
+                    }
+                    None => api.send_message(format_args!("There is no emote {emote_name}.")),
+                }
+                Ok(())
+            }
+            "topemotes" => {
+                let top = self.top(&api.connection(), TOP_IN_CHAT)?;
+                if top.is_empty() {
+                    api.send_message("Nobody has used any emotes yet.");
+                    return Ok(());
+                }
+
+                let list = top
+                    .iter()
+                    .enumerate()
+                    .map(|(index, (name, uses))| format!("{}. {name} ({uses})", index + 1))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                api.send_message(format_args!("Top emotes: {list}"));
                 Ok(())
             }
             "emotes" => {
                 let page = self.render_emote_page(&api.connection())?;
-                let url = neocities::publish("emotes.html", &page)?;
+                let url = api.publish("emotes.html", &page)?;
                 api.send_message(url);
                 Ok(())
             }
             _ => Ok(()),
         }
     }
+
+    fn handle(&mut self, api: Api, message: &MessageType) -> anyhow::Result<()> {
+        let MessageType::ChatMessage(message) = message else {
+            return Ok(());
+        };
+        if !self.config.inline || message.command().is_some() {
+            return Ok(());
+        }
+        let is_bot = api
+            .state()
+            .user
+            .as_ref()
+            .is_some_and(|user| user.id == message.user_id);
+        if is_bot {
+            return Ok(());
+        }
+
+        for url in self.expand_inline(&api.connection(), message, Instant::now())? {
+            api.send_message(url);
+        }
+        Ok(())
+    }
+
+    fn reload(&mut self, _api: Api, config: &Config) -> anyhow::Result<()> {
+        self.config = config.emotes.clone();
+        Ok(())
+    }
+
+    fn shutdown(&mut self, _api: Api) -> anyhow::Result<()> {
+        if let Some(check) = self.check.take() {
+            check.stop.store(true, Ordering::Relaxed);
+            if check.thread.join().is_err() {
+                log::warn!("checking emotes panicked");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_emote_url, check_emote_urls, describe_emote, inline_emote_names, Aliased, Deleted,
+        EmoteConfig, EmoteInfo, EmoteUrlError, Emotes, Renamed,
+    };
+    use crate::api::mock::MockHttp;
+    use crate::command::{Commands, Dispatch};
+    use crate::handler::{ChatMessage, Handler};
+    use crate::migrations::test_db;
+    use rusqlite::Connection;
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicBool;
+    use std::time::{Duration, Instant};
+    use ureq::AgentBuilder;
+
+    fn setup() -> anyhow::Result<(Connection, Emotes)> {
+        let db = test_db()?;
+        Ok((db, Emotes::new(EmoteConfig::default())))
+    }
+
+    #[test]
+    fn emote_arguments() {
+        let handlers: Vec<Box<dyn Handler + Send>> =
+            vec![Box::new(Emotes::new(EmoteConfig::default()))];
+        let commands = Commands::new(&handlers, "!");
+        let message = ChatMessage::test("u1", "!e dance because it is friday");
+        let Some(Dispatch::Handler(0, invocation)) = commands.dispatch(&message) else {
+            panic!("expected the emote command to be accepted");
+        };
+        assert_eq!(invocation.arguments[0], "dance");
+    }
+
+    #[test]
+    fn manage_emotes() -> anyhow::Result<()> {
+        let (db, emotes) = setup()?;
+
+        assert!(emotes.insert_emote(&db, "dance", "https://wlk.yt/dance.gif", "u1", 0)?);
+        assert!(!emotes.insert_emote(&db, "dance", "https://wlk.yt/other.gif", "u1", 0)?);
+        let dance = || Aliased::Alias {
+            emote: "dance".to_string(),
+        };
+        assert_eq!(emotes.alias_emote(&db, "boogie", "dance")?, dance());
+        // Aliases of aliases point at the emote.
+        assert_eq!(emotes.alias_emote(&db, "groove", "boogie")?, dance());
+        assert_eq!(emotes.alias_emote(&db, "dance", "boogie")?, Aliased::Exists);
+        assert_eq!(
+            emotes.alias_emote(&db, "spin", "nothing")?,
+            Aliased::Nothing
+        );
+        assert!(!emotes.insert_emote(&db, "boogie", "https://wlk.yt/other.gif", "u1", 0)?);
+
+        assert!(emotes.update_emote(&db, "groove", "https://wlk.yt/dance2.gif")?);
+        assert!(!emotes.update_emote(&db, "nothing", "https://wlk.yt/other.gif")?);
+        assert_eq!(
+            emotes.get_emote(&db, "boogie")?.as_deref(),
+            Some("https://wlk.yt/dance2.gif")
+        );
+
+        assert_eq!(emotes.rename_emote(&db, "dance", "dancing")?, Renamed::Done);
+        assert_eq!(emotes.get_emote(&db, "dance")?, None);
+        assert_eq!(
+            emotes.resolve_name(&db, "boogie")?.as_deref(),
+            Some("dancing")
+        );
+        emotes.rename_emote(&db, "groove", "grooving")?;
+        assert_eq!(
+            emotes.resolve_name(&db, "grooving")?.as_deref(),
+            Some("dancing")
+        );
+        assert_eq!(
+            emotes.rename_emote(&db, "boogie", "dancing")?,
+            Renamed::Exists
+        );
+        assert_eq!(
+            emotes.rename_emote(&db, "nothing", "something")?,
+            Renamed::Nothing
+        );
+
+        assert_eq!(
+            emotes.delete_emote(&db, "boogie")?,
+            Deleted::Alias {
+                emote: "dancing".to_string()
+            }
+        );
+        assert!(emotes.get_emote(&db, "dancing")?.is_some());
+        assert_eq!(emotes.delete_emote(&db, "dancing")?, Deleted::Emote);
+        assert_eq!(emotes.get_emote(&db, "grooving")?, None);
+        assert_eq!(emotes.delete_emote(&db, "dancing")?, Deleted::Nothing);
+        Ok(())
+    }
+
+    #[test]
+    fn emote_stats() -> anyhow::Result<()> {
+        let (db, emotes) = setup()?;
+        let day = 24 * 60 * 60;
+
+        emotes.insert_emote(&db, "dance", "https://wlk.yt/dance.gif", "u1", day)?;
+        emotes.insert_emote(&db, "wave", "https://wlk.yt/wave.gif", "u2", day)?;
+        emotes.alias_emote(&db, "boogie", "dance")?;
+        assert_eq!(emotes.top(&db, 5)?, []);
+
+        assert!(emotes.use_emote(&db, "boogie", "u2", 2 * day)?.is_some());
+        assert!(emotes.use_emote(&db, "dance", "u3", 2 * day)?.is_some());
+        assert!(emotes.use_emote(&db, "wave", "u3", 2 * day)?.is_some());
+        assert_eq!(emotes.use_emote(&db, "nothing", "u3", 2 * day)?, None);
+        assert_eq!(
+            emotes.top(&db, 5)?,
+            [("dance".to_string(), 2), ("wave".to_string(), 1)]
+        );
+
+        // Uses follow renames.
+        emotes.rename_emote(&db, "wave", "hello")?;
+        let info = emotes.emote_info(&db, "hello")?.unwrap();
+        assert_eq!(
+            info,
+            EmoteInfo {
+                name: "hello".to_string(),
+                added_by: Some("u2".to_string()),
+                added_at: Some(day),
+                uses: 1,
+            }
+        );
+        assert_eq!(
+            describe_emote(&info, Some("Bob"), 3 * day),
+            "hello was added by Bob 2 days ago and has been used once."
+        );
+
+        let info = emotes.emote_info(&db, "boogie")?.unwrap();
+        assert_eq!(info.name, "dance");
+        assert_eq!(
+            describe_emote(&info, None, 3 * day),
+            "dance has been used 2 times."
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn emote_urls() -> anyhow::Result<()> {
+        let agent = AgentBuilder::new().build();
+        let server = MockHttp::start_raw(vec![
+            (200, "image/gif", b"GIF89a".to_vec()),
+            (200, "video/webm", vec![0; 16]),
+            (200, "text/html; charset=utf-8", b"<html></html>".to_vec()),
+            (404, "text/plain", b"Not Found".to_vec()),
+            (200, "image/png", vec![0; 100]),
+        ]);
+        let url = format!("{}/emote", server.url());
+
+        assert_eq!(check_emote_url(&agent, &url, 50), Ok(()));
+        assert_eq!(check_emote_url(&agent, &url, 50), Ok(()));
+        assert_eq!(
+            check_emote_url(&agent, &url, 50),
+            Err(EmoteUrlError::ContentType("text/html".to_string()))
+        );
+        assert_eq!(
+            check_emote_url(&agent, &url, 50),
+            Err(EmoteUrlError::Status(404))
+        );
+        assert_eq!(
+            check_emote_url(&agent, &url, 50),
+            Err(EmoteUrlError::TooLarge(100))
+        );
+        let requests = server.requests();
+        assert_eq!(requests.len(), 5);
+        assert_eq!(requests[0].method, "HEAD");
+        assert_eq!(requests[0].path, "/emote");
+
+        // Servers that do not support HEAD get a GET request for the first byte.
+        let server = MockHttp::start_raw(vec![
+            (405, "text/plain", b"Method Not Allowed".to_vec()),
+            (200, "image/png", vec![0; 100]),
+        ]);
+        let url = format!("{}/emote", server.url());
+        assert_eq!(
+            check_emote_url(&agent, &url, 50),
+            Err(EmoteUrlError::TooLarge(100))
+        );
+        let requests = server.requests();
+        assert_eq!(requests[1].method, "GET");
+
+        // Files of unknown size are not downloaded to find out.
+        let server = MockHttp::start_unsized(vec![(200, "image/gif", vec![0; 100])]);
+        let url = format!("{}/emote", server.url());
+        assert_eq!(check_emote_url(&agent, &url, 50), Ok(()));
+        assert_eq!(server.requests()[0].method, "HEAD");
+
+        // A stopped check does not make any requests.
+        let emotes = [("dance".to_string(), url)];
+        assert_eq!(
+            check_emote_urls(&agent, &emotes, &AtomicBool::new(true)),
+            None
+        );
+
+        assert_eq!(
+            check_emote_url(&agent, "ftp://wlk.yt/dance.gif", 50),
+            Err(EmoteUrlError::Invalid("ftp://wlk.yt/dance.gif".to_string()))
+        );
+        assert!(check_emote_url(&agent, "dance.gif", 50).is_err());
+
+        // Nothing listens on this port any more.
+        let address = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let err = check_emote_url(&agent, &format!("http://{address}/"), 50).unwrap_err();
+        assert!(err.is_temporary());
+        Ok(())
+    }
+
+    #[test]
+    fn fuzzy_lookup() -> anyhow::Result<()> {
+        let (db, emotes) = setup()?;
+        assert_eq!(emotes.use_random_emote(&db, "u1", 0)?, None);
+
+        emotes.insert_emote(&db, "dance", "https://wlk.yt/dance.gif", "u1", 0)?;
+        emotes.insert_emote(&db, "Dance", "https://wlk.yt/Dance.gif", "u1", 0)?;
+        emotes.insert_emote(&db, "prance", "https://wlk.yt/prance.gif", "u1", 0)?;
+        emotes.insert_emote(&db, "wave", "https://wlk.yt/wave.gif", "u1", 0)?;
+        emotes.alias_emote(&db, "Boogie", "prance")?;
+
+        // Exact matches win over case-insensitive ones.
+        assert_eq!(
+            emotes.use_emote(&db, "Dance", "u1", 0)?.as_deref(),
+            Some("https://wlk.yt/Dance.gif")
+        );
+        assert_eq!(
+            emotes.use_emote(&db, "WAVE", "u1", 0)?.as_deref(),
+            Some("https://wlk.yt/wave.gif")
+        );
+        assert_eq!(
+            emotes.use_emote(&db, "boogie", "u1", 0)?.as_deref(),
+            Some("https://wlk.yt/prance.gif")
+        );
+        assert_eq!(emotes.use_emote(&db, "dnace", "u1", 0)?, None);
+        // Names that only differ in case resolve the same way every time.
+        emotes.insert_emote(&db, "kappa", "https://wlk.yt/kappa.gif", "u1", 0)?;
+        emotes.insert_emote(&db, "Kappa", "https://wlk.yt/Kappa.gif", "u1", 0)?;
+        emotes.alias_emote(&db, "wiggle", "wave")?;
+        emotes.alias_emote(&db, "Wiggle", "prance")?;
+        assert_eq!(emotes.find_name(&db, "KAPPA")?.as_deref(), Some("Kappa"));
+        assert_eq!(emotes.find_name(&db, "WIGGLE")?.as_deref(), Some("prance"));
+
+        assert_eq!(emotes.suggest(&db, "dnace")?, ["Dance", "dance", "prance"]);
+        assert_eq!(emotes.suggest(&db, "wav")?, ["wave"]);
+        assert!(emotes.suggest(&db, "xyz")?.is_empty());
+
+        let (name, url) = emotes.use_random_emote(&db, "u1", 0)?.unwrap();
+        assert_eq!(emotes.get_emote(&db, &name)?, Some(url));
+        Ok(())
+    }
+
+    #[test]
+    fn inline_emotes() -> anyhow::Result<()> {
+        assert_eq!(
+            inline_emote_names("at 12:30 :wave: :dance::wave: :not an emote:"),
+            ["wave", "dance"]
+        );
+        assert!(inline_emote_names("no emotes: here").is_empty());
+
+        let (db, mut emotes) = setup()?;
+        emotes.config = EmoteConfig {
+            inline: true,
+            inline_limit: 2,
+            inline_cooldown: Duration::from_secs(30),
+        };
+        for name in ["dance", "wave", "spin"] {
+            let url = format!("https://wlk.yt/{name}.gif");
+            emotes.insert_emote(&db, name, &url, "u1", 0)?;
+        }
+
+        let start = Instant::now();
+        assert_eq!(
+            emotes.expand_inline(
+                &db,
+                &ChatMessage::test("u1", ":nothing: :spin: :wave: :dance:"),
+                start
+            )?,
+            ["https://wlk.yt/spin.gif", "https://wlk.yt/wave.gif"]
+        );
+        // The user is on cooldown, others are not.
+        let later = start + Duration::from_secs(10);
+        assert!(emotes
+            .expand_inline(&db, &ChatMessage::test("u1", ":dance:"), later)?
+            .is_empty());
+        assert_eq!(
+            emotes.expand_inline(&db, &ChatMessage::test("u2", ":DANCE:"), later)?,
+            ["https://wlk.yt/dance.gif"]
+        );
+        // Messages without known emotes do not start a cooldown.
+        assert!(emotes
+            .expand_inline(&db, &ChatMessage::test("u3", ":nothing:"), later)?
+            .is_empty());
+        assert_eq!(
+            emotes.expand_inline(&db, &ChatMessage::test("u3", ":wave:"), later)?,
+            ["https://wlk.yt/wave.gif"]
+        );
+
+        let after_cooldown = start + Duration::from_secs(30);
+        assert_eq!(
+            emotes.expand_inline(&db, &ChatMessage::test("u1", ":dance:"), after_cooldown)?,
+            ["https://wlk.yt/dance.gif"]
+        );
+        assert_eq!(
+            emotes.top(&db, 3)?,
+            [
+                ("dance".to_string(), 2),
+                ("wave".to_string(), 2),
+                ("spin".to_string(), 1)
+            ]
+        );
+        Ok(())
+    }
 }
@@ -1,24 +1,21 @@
-use crate::handler::{Api, ChatCommand, Handler, MessageType};
+use crate::command::{Command, Invocation};
+use crate::handler::{Api, Handler};
 
 #[derive(Debug, Default)]
 pub struct Exit;
 
 impl Handler for Exit {
-    fn handle(&mut self, api: Api, message: &MessageType) -> anyhow::Result<()> {
-        let message = match message {
-            MessageType::ChatMessage(message) => message,
-            _ => return Ok(()),
-        };
-        let ChatCommand { command, .. } = if let Some(c) = message.command() {
-            c
-        } else {
-            return Ok(());
-        };
+    fn name(&self) -> &'static str {
+        "exit"
+    }
 
-        if command.as_str() == "exit" {
-            api.exit();
-        }
+    fn commands(&self) -> &'static [Command] {
+        const COMMANDS: &[Command] = &[Command::new("exit", "Shut down the bot.").role("manager")];
+        COMMANDS
+    }
 
+    fn handle_command(&mut self, api: Api, _invocation: &Invocation) -> anyhow::Result<()> {
+        api.exit();
         Ok(())
     }
 }
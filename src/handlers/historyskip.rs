@@ -1,60 +1,864 @@
-use crate::api::uwave::{HistoryOptions, SkipOptions};
-use crate::handler::{Api, Handler, MessageType};
-use anyhow::Result;
-use chrono::{Duration, Utc};
+use super::skiplist::Media;
+use crate::api::uwave::{
+    BaseMedia, HistoryEntry, HistoryOptions, MediaWithOverrides, Pagination, SkipOptions,
+};
+use crate::command::{Argument, Command, Invocation};
+use crate::config::Config;
+use crate::handler::{AdvanceMessage, Api, Handler, MessageType};
+use crate::settings;
+use crate::similarity::similarity;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
 use chrono_humanize::{Accuracy, HumanTime, Tense};
+use rusqlite::{params, Connection, OptionalExtension as _};
+use serde::{Deserialize, Deserializer};
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
 
-#[derive(Debug, Default)]
+/// Maximum number of recent plays to compare with when looking for other uploads of a song.
+const FUZZY_HISTORY_SIZE: usize = 50;
+/// Namespace for runtime changes to the policy in the settings table.
+const SETTINGS_NAMESPACE: &str = "historyskip";
+
+/// Parse a duration like `90m`, `1h` or `3600`. A number without a unit is in seconds.
+fn parse_duration(s: &str) -> Result<Duration> {
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let number: u64 = number.parse()?;
+    let multiplier = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("unknown duration unit `{unit}`. expected `s`, `m`, `h` or `d`"),
+    };
+    let Some(seconds) = number.checked_mul(multiplier) else {
+        bail!("{s} is too long");
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0 => "0s".to_string(),
+        _ if seconds % (24 * 60 * 60) == 0 => format!("{}d", seconds / (24 * 60 * 60)),
+        _ if seconds % (60 * 60) == 0 => format!("{}h", seconds / (60 * 60)),
+        _ if seconds % 60 == 0 => format!("{}m", seconds / 60),
+        _ => format!("{seconds}s"),
+    }
+}
+
+fn parse_fraction(key: &str, value: &str) -> Result<f64> {
+    let fraction: f64 = value.parse()?;
+    if !(0.0..=1.0).contains(&fraction) {
+        bail!("{key} must be between 0 and 1");
+    }
+    Ok(fraction)
+}
+
+pub(crate) fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_duration(&s).map_err(serde::de::Error::custom)
+}
+
+/// How to handle songs that look like a recent play of a different media.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FuzzyMode {
+    /// Only skip exact repeats of the same media.
+    Off,
+    /// Mention the likely repeat in chat, but don't skip it.
+    Warn,
+    /// Skip likely repeats like exact repeats.
+    Skip,
+}
+
+impl FromStr for FuzzyMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(Self::Off),
+            "warn" => Ok(Self::Warn),
+            "skip" => Ok(Self::Skip),
+            _ => bail!("unknown fuzzy mode. expected `off`, `warn` or `skip`"),
+        }
+    }
+}
+
+impl Display for FuzzyMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Off => "off",
+            Self::Warn => "warn",
+            Self::Skip => "skip",
+        })
+    }
+}
+
+/// Words in parenthesized parts of titles that only describe the upload, not the song.
+const UPLOAD_NOISE: &[&str] = &[
+    "mv",
+    "m/v",
+    "official",
+    "video",
+    "audio",
+    "lyric",
+    "teaser",
+    "visualizer",
+    "hd",
+    "4k",
+];
+
+/// Normalize an artist or title so different uploads of the same song compare equal.
+fn normalize_song(s: &str) -> String {
+    let s = s.to_lowercase();
+    let mut kept = String::new();
+    let mut rest = s.as_str();
+    while let Some(open) = rest.find(['(', '[', '【']) {
+        let (start, close) = match rest[open..].chars().next() {
+            Some('(') => (open + 1, ')'),
+            Some('[') => (open + 1, ']'),
+            _ => (open + '【'.len_utf8(), '】'),
+        };
+        let Some(length) = rest[open..].find(close) else {
+            break;
+        };
+        let group = &rest[start..open + length];
+        let is_noise = group.starts_with("feat")
+            || group.starts_with("ft.")
+            || UPLOAD_NOISE
+                .iter()
+                .any(|noise| group.split_whitespace().any(|word| word == *noise));
+        kept.push_str(&rest[..open]);
+        if !is_noise {
+            kept.push_str(group);
+        }
+        rest = &rest[open + length + close.len_utf8()..];
+    }
+    kept.push_str(rest);
+
+    // Unbracketed featuring credits run until the end.
+    for credit in [" feat. ", " feat ", " ft. ", " featuring "] {
+        if let Some(index) = kept.find(credit) {
+            kept.truncate(index);
+        }
+    }
+
+    // Spacing differs a lot between uploads, especially in hangul and romanizations.
+    kept.chars().filter(|c| c.is_alphanumeric()).collect()
+}
+
+/// How likely two plays are the same song, from 0 to 1.
+fn song_similarity<T>(a: &MediaWithOverrides<T>, b: &MediaWithOverrides<T>) -> f64 {
+    let a = normalize_song(&a.artist) + &normalize_song(&a.title);
+    let b = normalize_song(&b.artist) + &normalize_song(&b.title);
+    similarity(&a, &b)
+}
+
+/// When and how recently played songs are skipped.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryPolicy {
+    /// Songs that were played less than this long ago are skipped.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub window: Duration,
+    /// Only look for repeats in this many of the most recent plays in the room.
+    pub recent_plays: Option<u32>,
+    /// Remove DJs from the waitlist when more than this many songs in a row are history skipped.
+    pub remove_after: usize,
+    /// Only skip songs that were last played by the same DJ.
+    pub same_dj: bool,
+    /// How much two plays of the same media must overlap to count as a repeat, from 0 to 1.
+    pub overlap: f64,
+    /// Chat message when skipping. `{time}`, `{dj}`, `{artist}` and `{title}` are filled in.
+    pub message: String,
+    /// Whether to look for other uploads of the same song.
+    pub fuzzy: FuzzyMode,
+    /// How similar the artist and title of another upload must be to count as a repeat, from 0
+    /// to 1.
+    pub fuzzy_threshold: f64,
+}
+
+impl Default for HistoryPolicy {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(60 * 60),
+            recent_plays: None,
+            remove_after: 3,
+            same_dj: false,
+            overlap: 0.5,
+            message: "This song was played {time}.".to_string(),
+            fuzzy: FuzzyMode::Off,
+            fuzzy_threshold: 0.85,
+        }
+    }
+}
+
+impl HistoryPolicy {
+    const KEYS: &'static [&'static str] = &[
+        "window",
+        "recent_plays",
+        "remove_after",
+        "same_dj",
+        "overlap",
+        "message",
+        "fuzzy",
+        "fuzzy_threshold",
+    ];
+
+    fn get(&self, key: &str) -> Option<String> {
+        let value = match key {
+            "window" => format_duration(self.window),
+            "recent_plays" => match self.recent_plays {
+                Some(count) => count.to_string(),
+                None => "off".to_string(),
+            },
+            "remove_after" => self.remove_after.to_string(),
+            "same_dj" => self.same_dj.to_string(),
+            "overlap" => self.overlap.to_string(),
+            "message" => self.message.clone(),
+            "fuzzy" => self.fuzzy.to_string(),
+            "fuzzy_threshold" => self.fuzzy_threshold.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "window" => self.window = parse_duration(value)?,
+            "recent_plays" => {
+                self.recent_plays = match value {
+                    "off" | "0" => None,
+                    count => Some(count.parse()?),
+                }
+            }
+            "remove_after" => self.remove_after = value.parse()?,
+            "same_dj" => self.same_dj = value.parse()?,
+            "overlap" => self.overlap = parse_fraction(key, value)?,
+            "message" => self.message = value.to_string(),
+            "fuzzy" => self.fuzzy = value.parse()?,
+            "fuzzy_threshold" => self.fuzzy_threshold = parse_fraction(key, value)?,
+            _ => bail!(
+                "unknown history skip setting `{key}`. expected one of: {}",
+                Self::KEYS.join(", ")
+            ),
+        }
+        Ok(())
+    }
+
+    fn render_message(
+        &self,
+        time: &str,
+        dj: &str,
+        media: &MediaWithOverrides<BaseMedia>,
+    ) -> String {
+        self.message
+            .replace("{time}", time)
+            .replace("{dj}", dj)
+            .replace("{artist}", &media.artist)
+            .replace("{title}", &media.title)
+    }
+}
+
+impl Display for HistoryPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (index, key) in Self::KEYS.iter().enumerate() {
+            if index > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{key}: {}", self.get(key).unwrap_or_default())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExemptionKind {
+    /// Never skip a specific media.
+    Media,
+    /// Never skip songs by an artist.
+    Artist,
+    /// Allow the next repeat of a specific media, or of any media if the value is empty.
+    Once,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("unknown exemption type. expected `media`, `artist` or `once`")]
+pub struct ParseExemptionKindError;
+
+impl FromStr for ExemptionKind {
+    type Err = ParseExemptionKindError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "media" => Ok(Self::Media),
+            "artist" => Ok(Self::Artist),
+            "once" => Ok(Self::Once),
+            _ => Err(ParseExemptionKindError),
+        }
+    }
+}
+
+impl Display for ExemptionKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Media => "media",
+            Self::Artist => "artist",
+            Self::Once => "once",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Exemption {
+    kind: ExemptionKind,
+    value: String,
+}
+
+impl Exemption {
+    /// Parse an exemption from command arguments, normalizing media IDs.
+    fn from_arguments(kind: &str, value: Option<&String>) -> Result<Self> {
+        let kind: ExemptionKind = kind.parse()?;
+        let value = match (kind, value) {
+            (ExemptionKind::Media, Some(value)) => value.parse::<Media>()?.to_string(),
+            (ExemptionKind::Artist, Some(value)) => value.to_string(),
+            (ExemptionKind::Once, Some(value)) => value.parse::<Media>()?.to_string(),
+            (ExemptionKind::Once, None) => String::new(),
+            (_, None) => anyhow::bail!("a {kind} exemption needs a value"),
+        };
+        Ok(Self { kind, value })
+    }
+}
+
+impl Display for Exemption {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match (self.kind, self.value.as_str()) {
+            (ExemptionKind::Once, "") => write!(f, "once for any song"),
+            (kind, value) => write!(f, "{kind} {value}"),
+        }
+    }
+}
+
+/// Returns how much of the played segments of two plays overlap, as a fraction of the longest
+/// segment.
+fn segment_overlap<T>(a: &MediaWithOverrides<T>, b: &MediaWithOverrides<T>) -> f64 {
+    let longest = a
+        .end
+        .saturating_sub(a.start)
+        .max(b.end.saturating_sub(b.start));
+    if longest == 0 {
+        return 1.0;
+    }
+    let shared = a.end.min(b.end).saturating_sub(a.start.max(b.start));
+    f64::from(shared) / f64::from(longest)
+}
+
+#[derive(Debug)]
 pub struct HistorySkip {
     consecutive_skip_count: usize,
+    /// The policy from the configuration, used when a setting is reset.
+    configured: HistoryPolicy,
+    /// The policy with changes made at runtime applied.
+    policy: HistoryPolicy,
 }
 
 impl HistorySkip {
-    pub fn new() -> Self {
-        Self::default()
+    pub fn new(configured: HistoryPolicy, db: &Connection) -> Result<Self> {
+        let mut history_skip = Self {
+            consecutive_skip_count: 0,
+            configured: HistoryPolicy::default(),
+            policy: HistoryPolicy::default(),
+        };
+        history_skip.set_configured(configured, db)?;
+        Ok(history_skip)
+    }
+
+    /// Use a new configured policy, keeping the changes that were made from chat.
+    fn set_configured(&mut self, configured: HistoryPolicy, db: &Connection) -> Result<()> {
+        let mut policy = configured.clone();
+        for (key, value) in settings::load(db, SETTINGS_NAMESPACE)? {
+            if let Err(err) = policy.set(&key, &value) {
+                log::warn!("ignoring stored history skip setting {key}={value:?}: {err}");
+            }
+        }
+        self.configured = configured;
+        self.policy = policy;
+        Ok(())
+    }
+
+    /// Change a policy setting and persist it. The value `default` restores the configured value.
+    fn configure(&mut self, db: &Connection, key: &str, value: &str) -> Result<()> {
+        if value == "default" {
+            let configured = self
+                .configured
+                .get(key)
+                .ok_or_else(|| anyhow::anyhow!("unknown history skip setting `{key}`"))?;
+            self.policy.set(key, &configured)?;
+            settings::remove(db, SETTINGS_NAMESPACE, key)
+        } else {
+            self.policy.set(key, value)?;
+            settings::store(db, SETTINGS_NAMESPACE, key, value)
+        }
+    }
+
+    fn add_exemption(&self, db: &Connection, exemption: &Exemption) -> Result<()> {
+        db.execute(
+            "INSERT OR IGNORE INTO historyskip_exemptions (kind, value) VALUES (?, ?)",
+            params![exemption.kind.to_string(), exemption.value],
+        )?;
+        Ok(())
+    }
+
+    /// Returns true if the exemption existed.
+    fn remove_exemption(&self, db: &Connection, exemption: &Exemption) -> Result<bool> {
+        let count = db.execute(
+            "DELETE FROM historyskip_exemptions
+            WHERE kind = ?1 AND (value = ?2 OR (kind = 'artist' AND value = ?2 COLLATE NOCASE))",
+            params![exemption.kind.to_string(), exemption.value],
+        )?;
+        Ok(count > 0)
+    }
+
+    fn list_exemptions(&self, db: &Connection) -> Result<Vec<Exemption>> {
+        let mut stmt =
+            db.prepare("SELECT kind, value FROM historyskip_exemptions ORDER BY kind, value")?;
+        let query = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))?;
+
+        let mut exemptions = vec![];
+        for row in query {
+            let (kind, value) = row?;
+            exemptions.push(Exemption {
+                kind: kind.parse()?,
+                value,
+            });
+        }
+        Ok(exemptions)
+    }
+
+    /// Find an exemption for a media. One-time exemptions are consumed.
+    fn take_exemption(
+        &self,
+        db: &Connection,
+        media: &MediaWithOverrides<BaseMedia>,
+    ) -> Result<Option<Exemption>> {
+        let media_id = Media::from(&media.media).to_string();
+        // Prefer permanent exemptions, so one-time exemptions are kept for later.
+        let exemption = db
+            .query_row(
+                "SELECT kind, value FROM historyskip_exemptions
+                WHERE (kind = 'media' AND value = ?1)
+                    OR (kind = 'artist' AND value = ?2 COLLATE NOCASE)
+                    OR (kind = 'once' AND value IN (?1, ''))
+                ORDER BY kind = 'once', value = ''
+                LIMIT 1",
+                params![media_id, media.artist],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()?;
+
+        let Some((kind, value)) = exemption else {
+            return Ok(None);
+        };
+        let exemption = Exemption {
+            kind: kind.parse()?,
+            value,
+        };
+        if exemption.kind == ExemptionKind::Once {
+            self.remove_exemption(db, &exemption)?;
+        }
+        Ok(Some(exemption))
+    }
+
+    /// Iterate over the plays since `since`, excluding the current one and respecting the
+    /// `recent_plays` limit. If `media` is given, only plays of that media are included.
+    fn recent_plays<'a>(
+        &self,
+        api: &'a Api,
+        message: &'a AdvanceMessage,
+        media: Option<String>,
+        since: DateTime<Utc>,
+    ) -> impl Iterator<Item = Result<HistoryEntry<BaseMedia>>> + 'a {
+        let count = self.policy.recent_plays;
+        api.http
+            .history_iter(HistoryOptions {
+                media,
+                since: Some(since),
+                pagination: count.map(|count| Pagination {
+                    offset: 0,
+                    limit: count + 1,
+                }),
+                ..Default::default()
+            })
+            .filter(move |entry| {
+                entry
+                    .as_ref()
+                    .map_or(true, |entry| entry.history_id != message.history_id)
+            })
+            .take(count.map_or(usize::MAX, |count| count as usize))
+    }
+
+    /// Find the most recent play of the same media.
+    fn find_repeat(
+        &self,
+        api: &Api,
+        message: &AdvanceMessage,
+        since: DateTime<Utc>,
+    ) -> Result<Option<HistoryEntry<BaseMedia>>> {
+        let policy = &self.policy;
+        // Without a limit on the number of plays, only the plays of this media are interesting.
+        let media = match policy.recent_plays {
+            Some(_) => None,
+            None => Some(message.media.media.id.clone()),
+        };
+        for entry in self.recent_plays(api, message, media, since) {
+            let entry = entry?;
+            if entry.media.media.id == message.media.media.id
+                && segment_overlap(&entry.media, &message.media) >= policy.overlap
+                && (!policy.same_dj || entry.user_id == message.user_id)
+            {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find the recent play of a different media that is most likely the same song, with how
+    /// confident the match is.
+    fn find_fuzzy_repeat(
+        &self,
+        api: &Api,
+        message: &AdvanceMessage,
+        since: DateTime<Utc>,
+    ) -> Result<Option<(HistoryEntry<BaseMedia>, f64)>> {
+        let policy = &self.policy;
+        let mut best: Option<(HistoryEntry<BaseMedia>, f64)> = None;
+        let plays = self
+            .recent_plays(api, message, None, since)
+            .take(FUZZY_HISTORY_SIZE);
+        for entry in plays {
+            let entry = entry?;
+            if entry.media.media.id == message.media.media.id
+                || (policy.same_dj && entry.user_id != message.user_id)
+            {
+                continue;
+            }
+            let confidence = song_similarity(&entry.media, &message.media);
+            if best.as_ref().is_none_or(|(_, best)| confidence > *best) {
+                best = Some((entry, confidence));
+            }
+        }
+
+        let Some((entry, confidence)) = best else {
+            return Ok(None);
+        };
+        log::info!(
+            "closest recent play to {} - {} is {} - {} ({}), confidence {confidence:.2}",
+            message.media.artist,
+            message.media.title,
+            entry.media.artist,
+            entry.media.title,
+            Media::from(&entry.media.media),
+        );
+        Ok(Some((entry, confidence)).filter(|_| confidence >= policy.fuzzy_threshold))
+    }
+
+    fn handle_advance(&mut self, api: Api, message: &AdvanceMessage) -> Result<()> {
+        let now = Utc::now();
+        let Some(since) = now.checked_sub_signed(chrono::Duration::from_std(self.policy.window)?)
+        else {
+            bail!("the history window is too long");
+        };
+        let repeat = match self.find_repeat(&api, message, since)? {
+            Some(entry) => Some((entry, None)),
+            None if self.policy.fuzzy != FuzzyMode::Off => self
+                .find_fuzzy_repeat(&api, message, since)?
+                .map(|(entry, confidence)| (entry, Some(confidence))),
+            None => None,
+        };
+
+        let Some((recent_entry, confidence)) = repeat else {
+            self.consecutive_skip_count = 0;
+            return Ok(());
+        };
+
+        let human_time =
+            HumanTime::from(now - recent_entry.played_at).to_text_en(Accuracy::Rough, Tense::Past);
+        if confidence.is_some() && self.policy.fuzzy == FuzzyMode::Warn {
+            api.send_message(format_args!(
+                "This song may have been played {human_time}, as {} - {}.",
+                recent_entry.media.artist, recent_entry.media.title
+            ));
+            self.consecutive_skip_count = 0;
+            return Ok(());
+        }
+
+        if let Some(exemption) = self.take_exemption(&api.connection(), &message.media)? {
+            log::info!("not skipping repeat because of exemption: {exemption}");
+            self.consecutive_skip_count = 0;
+            return Ok(());
+        }
+
+        log::info!("skipping because this song was played {human_time}");
+        let dj = api
+            .state()
+            .find_user(&message.user_id)
+            .map(|user| user.username.clone())
+            .unwrap_or_default();
+        api.send_message(self.policy.render_message(&human_time, &dj, &message.media));
+
+        self.consecutive_skip_count += 1;
+        api.http.skip(SkipOptions {
+            reason: Some("history".to_string()),
+            user_id: message.user_id.clone(),
+            remove: self.consecutive_skip_count > self.policy.remove_after,
+        })?;
+
+        Ok(())
     }
 }
 
 impl Handler for HistorySkip {
+    fn name(&self) -> &'static str {
+        "historyskip"
+    }
+
+    fn commands(&self) -> &'static [Command] {
+        const EXEMPTION: &[Argument] = &[Argument::required("type"), Argument::optional("value")];
+        const COMMANDS: &[Command] = &[
+            Command::new(
+                "historyskip exempt",
+                "Exempt a media (sourcetype:id) or artist from history skips. `once` allows the next repeat of a media, or of any song if no media is given.",
+            )
+            .arguments(EXEMPTION)
+            .role("moderator"),
+            Command::new("historyskip unexempt", "Remove a history skip exemption.")
+                .arguments(EXEMPTION)
+                .role("moderator"),
+            Command::new("historyskip list", "List the history skip exemptions."),
+            Command::new(
+                "historyskip config",
+                "Show or change the history skip settings. Use `default` as the value to restore the configured setting.",
+            )
+            .arguments(&[Argument::optional("setting"), Argument::rest("value")])
+            .role("moderator"),
+        ];
+        COMMANDS
+    }
+
+    fn handle_command(&mut self, api: Api, invocation: &Invocation) -> Result<()> {
+        let db = api.connection();
+        match (invocation.command.name, invocation.arguments) {
+            ("historyskip exempt", [kind, value @ ..]) => {
+                let exemption = Exemption::from_arguments(kind, value.first())?;
+                self.add_exemption(&db, &exemption)?;
+                api.send_message(format_args!("Added history skip exemption: {exemption}"));
+            }
+            ("historyskip unexempt", [kind, value @ ..]) => {
+                let exemption = Exemption::from_arguments(kind, value.first())?;
+                if self.remove_exemption(&db, &exemption)? {
+                    api.send_message(format_args!("Removed history skip exemption: {exemption}"));
+                } else {
+                    api.send_message(format_args!(
+                        "There is no history skip exemption for {exemption}"
+                    ));
+                }
+            }
+            ("historyskip list", _) => {
+                let exemptions = self.list_exemptions(&db)?;
+                if exemptions.is_empty() {
+                    api.send_message("There are no history skip exemptions.");
+                } else {
+                    let list = exemptions
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    api.send_message(format_args!("History skip exemptions: {list}"));
+                }
+            }
+            ("historyskip config", []) => {
+                api.send_message(format_args!("History skip settings: {}", self.policy));
+            }
+            ("historyskip config", [key]) => match self.policy.get(key) {
+                Some(value) => api.send_message(format_args!("{key}: {value}")),
+                None => api.send_message(format_args!(
+                    "Unknown setting. Available settings: {}",
+                    HistoryPolicy::KEYS.join(", ")
+                )),
+            },
+            ("historyskip config", [key, value @ ..]) => {
+                self.configure(&db, key, &value.join(" "))?;
+                let value = self.policy.get(key).unwrap_or_default();
+                api.send_message(format_args!("Set history skip {key} to {value}"));
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
     fn handle(&mut self, api: Api, message: &MessageType) -> Result<()> {
-        let message = match message {
-            MessageType::Advance(advance) => advance,
-            _ => return Ok(()),
+        match message {
+            MessageType::Advance(advance) => self.handle_advance(api, advance),
+            _ => Ok(()),
+        }
+    }
+
+    fn reload(&mut self, api: Api, config: &Config) -> Result<()> {
+        self.set_configured(config.historyskip.clone(), &api.connection())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        normalize_song, segment_overlap, song_similarity, Exemption, ExemptionKind, HistoryPolicy,
+        HistorySkip,
+    };
+    use crate::api::uwave::{BaseMedia, MediaWithOverrides};
+    use crate::migrations::test_db;
+
+    fn media(source_id: &str, artist: &str) -> MediaWithOverrides<BaseMedia> {
+        segment(source_id, artist, 0, 200)
+    }
+
+    fn segment(
+        source_id: &str,
+        artist: &str,
+        start: u32,
+        end: u32,
+    ) -> MediaWithOverrides<BaseMedia> {
+        MediaWithOverrides {
+            media: BaseMedia {
+                id: source_id.to_string(),
+                source_type: "youtube".to_string(),
+                source_id: source_id.to_string(),
+                artist: artist.to_string(),
+                title: "Title".to_string(),
+                duration: 200,
+            },
+            artist: artist.to_string(),
+            title: "Title".to_string(),
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn overlap() {
+        let full = segment("abc", "IU", 0, 200);
+        assert_eq!(segment_overlap(&full, &full), 1.0);
+        // A short intro clip is not a repeat of the full song.
+        assert_eq!(segment_overlap(&segment("abc", "IU", 0, 30), &full), 0.15);
+        assert_eq!(segment_overlap(&segment("abc", "IU", 20, 200), &full), 0.9);
+        assert_eq!(
+            segment_overlap(
+                &segment("abc", "IU", 0, 100),
+                &segment("abc", "IU", 100, 200)
+            ),
+            0.0
+        );
+    }
+
+    #[test]
+    fn fuzzy_match() {
+        assert_eq!(normalize_song("Palette (feat. G-DRAGON) [MV]"), "palette");
+        assert_eq!(normalize_song("Blueming (Official Video)"), "blueming");
+        assert_eq!(normalize_song("Lie (Heartbreak)"), "lieheartbreak");
+        assert_eq!(normalize_song("Celebrity feat. Someone"), "celebrity");
+        assert_eq!(normalize_song("밤편지 【M/V】"), "밤편지");
+        assert_eq!(normalize_song("밤 편지"), "밤편지");
+
+        let song = |artist: &str, title: &str| {
+            let mut media = media("abc", artist);
+            media.title = title.to_string();
+            media
         };
+        let original = song("IU", "Palette (feat. G-DRAGON)");
+        assert_eq!(
+            song_similarity(&original, &song("iu", "PALETTE (Official Video)")),
+            1.0
+        );
+        assert!(song_similarity(&original, &song("IU", "Palete")) > 0.85);
+        assert!(song_similarity(&original, &song("IU", "Blueming")) < 0.5);
+    }
 
-        let results = api.http.history(HistoryOptions {
-            media: Some(message.media.media.id.clone()),
+    #[test]
+    fn policy_settings() -> anyhow::Result<()> {
+        let db = test_db()?;
+        let configured = HistoryPolicy {
+            remove_after: 5,
             ..Default::default()
-        })?;
+        };
 
-        let recent_entry = results
-            .into_iter()
-            // Ignore the currently playing entry.
-            .skip(1)
-            .find(|entry| entry.media.media.id == message.media.media.id);
+        let mut historyskip = HistorySkip::new(configured.clone(), &db)?;
+        historyskip.configure(&db, "window", "90m")?;
+        historyskip.configure(&db, "remove_after", "2")?;
+        historyskip.configure(&db, "message", "Played {time} by {dj}")?;
+        assert!(historyskip.configure(&db, "window", "1y").is_err());
+        assert!(historyskip
+            .configure(&db, "window", "999999999999999999d")
+            .is_err());
+        assert!(historyskip.configure(&db, "overlap", "2").is_err());
+        assert!(historyskip.configure(&db, "nonsense", "1").is_err());
+        assert_eq!(historyskip.policy.get("window").as_deref(), Some("90m"));
 
-        let Some(recent_entry) = recent_entry else {
-            self.consecutive_skip_count = 0;
-            return Ok(())
-        };
-
-        let time = recent_entry.played_at;
-        let ago = Utc::now() - time;
-        if ago < Duration::hours(1) {
-            let human_time = HumanTime::from(ago).to_text_en(Accuracy::Rough, Tense::Past);
-            log::info!("skipping because this song was played {human_time}");
-
-            self.consecutive_skip_count += 1;
-            api.send_message(format!("This song was played {human_time}."));
-            api.http.skip(SkipOptions {
-                reason: Some("history".to_string()),
-                user_id: message.user_id.clone(),
-                remove: self.consecutive_skip_count > 3,
-            })?;
-        } else {
-            self.consecutive_skip_count = 0;
-        }
+        // Runtime changes are persisted, and can be reset to the configured values.
+        let mut historyskip = HistorySkip::new(configured, &db)?;
+        assert_eq!(historyskip.policy.window.as_secs(), 90 * 60);
+        assert_eq!(historyskip.policy.remove_after, 2);
+        historyskip.configure(&db, "remove_after", "default")?;
+        assert_eq!(historyskip.policy.remove_after, 5);
+        assert_eq!(
+            historyskip
+                .policy
+                .render_message("1 hour ago", "Alice", &media("abc", "IU")),
+            "Played 1 hour ago by Alice"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn exemptions() -> anyhow::Result<()> {
+        let db = test_db()?;
+        let historyskip = HistorySkip::new(HistoryPolicy::default(), &db)?;
+
+        let exempt = |kind: &str, value: Option<&str>| {
+            let exemption =
+                Exemption::from_arguments(kind, value.map(ToString::to_string).as_ref())?;
+            historyskip.add_exemption(&db, &exemption)
+        };
+        exempt("artist", Some("IU"))?;
+        exempt("once", Some("youtube:abc"))?;
+        exempt("once", None)?;
+        assert!(Exemption::from_arguments("media", Some(&"not-a-media-id".to_string())).is_err());
+        assert!(Exemption::from_arguments("media", None).is_err());
+
+        let take = |source_id: &str, artist: &str| {
+            historyskip
+                .take_exemption(&db, &media(source_id, artist))
+                .unwrap()
+                .map(|exemption| exemption.kind)
+        };
+        // Permanent exemptions are used first, and never consumed.
+        assert_eq!(take("abc", "iu"), Some(ExemptionKind::Artist));
+        assert_eq!(take("abc", "iu"), Some(ExemptionKind::Artist));
+        // Media-specific one-time exemptions are used before the catch-all one.
+        assert_eq!(take("abc", "SNSD"), Some(ExemptionKind::Once));
+        assert_eq!(take("def", "SNSD"), Some(ExemptionKind::Once));
+        assert_eq!(take("abc", "SNSD"), None);
+        assert_eq!(historyskip.list_exemptions(&db)?.len(), 1);
 
+        // Media IDs are case-sensitive, so consuming one does not remove the other.
+        exempt("once", Some("youtube:ABC"))?;
+        exempt("once", Some("youtube:abc"))?;
+        assert_eq!(take("ABC", "SNSD"), Some(ExemptionKind::Once));
+        assert_eq!(take("abc", "SNSD"), Some(ExemptionKind::Once));
+        assert_eq!(take("abc", "SNSD"), None);
         Ok(())
     }
 }
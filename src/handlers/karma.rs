@@ -0,0 +1,409 @@
+use super::page::render_page;
+use crate::api::uwave::{BaseMedia, HistoryEntry, HistoryOptions, NowState};
+use crate::command::{Argument, Command, Invocation};
+use crate::handler::{Api, Handler, MessageType};
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use chrono_humanize::{Accuracy, HumanTime, Tense};
+use rusqlite::{params, Connection, OptionalExtension as _};
+use std::fmt::Write as _;
+
+/// How long a user has to wait between giving props, in seconds.
+const PROPS_COOLDOWN: i64 = 10 * 60;
+/// Karma awarded to a DJ for each upvote on their play.
+const UPVOTE_KARMA: i64 = 1;
+/// Karma awarded to a DJ for each favorite on their play.
+const FAVORITE_KARMA: i64 = 2;
+/// Number of users listed by `!karmatop` in chat.
+const TOP_IN_CHAT: usize = 5;
+/// How many recent plays to look through for the one that ended. More may have started by the
+/// time it is handled.
+const HISTORY_SEARCH_LIMIT: usize = 100;
+
+/// Find the play with `history_id` in the history, most recent first. It is only returned if it
+/// ended when `next` started, or if nothing was played after it when `next` is `None`. Otherwise
+/// the handler missed some plays, for example while the module was disabled.
+fn find_ended(
+    history: impl Iterator<Item = Result<HistoryEntry<BaseMedia>>>,
+    history_id: &str,
+    next: Option<&str>,
+) -> Result<Option<HistoryEntry<BaseMedia>>> {
+    // The play that started right after the one being looked at.
+    let mut newer: Option<String> = None;
+    for entry in history.take(HISTORY_SEARCH_LIMIT) {
+        let entry = entry?;
+        if entry.history_id == history_id {
+            if newer.as_deref() != next {
+                log::warn!("not awarding karma for {history_id}, other plays were missed");
+                return Ok(None);
+            }
+            return Ok(Some(entry));
+        }
+        newer = Some(entry.history_id);
+    }
+    log::warn!("could not find history entry {history_id} to award karma");
+    Ok(None)
+}
+
+#[derive(Debug)]
+pub struct Karma {
+    /// The history entry that is currently playing. DJ karma for it is awarded once it ends.
+    current_history_id: Option<String>,
+}
+
+impl Karma {
+    pub fn new(now: &NowState) -> Self {
+        Self {
+            current_history_id: now.booth.as_ref().map(|booth| booth.history_id.clone()),
+        }
+    }
+
+    /// Add karma to a user, returning their new total.
+    fn add_karma(
+        &self,
+        db: &Connection,
+        user_id: &str,
+        username: &str,
+        amount: i64,
+    ) -> Result<i64> {
+        db.execute(
+            "INSERT INTO karma (user_id, username, karma) VALUES (?1, ?2, ?3)
+            ON CONFLICT (user_id) DO UPDATE SET username = ?2, karma = karma + ?3",
+            params![user_id, username, amount],
+        )?;
+        self.get_karma(db, user_id)
+    }
+
+    fn get_karma(&self, db: &Connection, user_id: &str) -> Result<i64> {
+        let karma = db
+            .query_row(
+                "SELECT karma FROM karma WHERE user_id = ?",
+                [user_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(karma.unwrap_or(0))
+    }
+
+    /// Find a user who has received karma before by their (last known) username.
+    fn find_by_username(&self, db: &Connection, username: &str) -> Result<Option<(String, i64)>> {
+        let entry = db
+            .query_row(
+                "SELECT username, karma FROM karma WHERE username = ? COLLATE NOCASE",
+                [username],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(entry)
+    }
+
+    fn top(&self, db: &Connection, limit: usize) -> Result<Vec<(String, i64)>> {
+        let mut stmt =
+            db.prepare("SELECT username, karma FROM karma ORDER BY karma DESC, username LIMIT ?")?;
+        let query = stmt.query_map([limit], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(query.collect::<Result<_, _>>()?)
+    }
+
+    /// Returns the number of seconds until `giver_id` can give props again.
+    fn props_cooldown(&self, db: &Connection, giver_id: &str, now: i64) -> Result<Option<i64>> {
+        let last_given: Option<i64> = db.query_row(
+            "SELECT MAX(given_at) FROM karma_props WHERE giver_id = ?",
+            [giver_id],
+            |row| row.get(0),
+        )?;
+        Ok(last_given
+            .map(|given_at| given_at + PROPS_COOLDOWN - now)
+            .filter(|remaining| *remaining > 0))
+    }
+
+    fn record_props(
+        &self,
+        db: &Connection,
+        giver_id: &str,
+        receiver_id: &str,
+        now: i64,
+    ) -> Result<()> {
+        db.execute(
+            "INSERT INTO karma_props (giver_id, receiver_id, given_at) VALUES (?, ?, ?)",
+            params![giver_id, receiver_id, now],
+        )?;
+        Ok(())
+    }
+
+    fn give_props(&mut self, api: Api, invocation: &Invocation) -> Result<()> {
+        let giver_id = &invocation.message.user_id;
+        let giver = api.username(giver_id)?;
+        let Some(booth) = api.state().booth.clone() else {
+            api.send_message(format_args!("@{giver} Nobody is playing right now."));
+            return Ok(());
+        };
+        if booth.user_id == *giver_id {
+            api.send_message(format_args!("@{giver} You can't give props to yourself."));
+            return Ok(());
+        }
+
+        let db = api.connection();
+        let now = Utc::now().timestamp();
+        if let Some(remaining) = self.props_cooldown(&db, giver_id, now)? {
+            let remaining = HumanTime::from(Duration::seconds(remaining))
+                .to_text_en(Accuracy::Rough, Tense::Future);
+            api.send_message(format_args!(
+                "@{giver} You can give props again {remaining}."
+            ));
+            return Ok(());
+        }
+
+        let dj = api.username(&booth.user_id)?;
+        self.record_props(&db, giver_id, &booth.user_id, now)?;
+        let karma = self.add_karma(&db, &booth.user_id, &dj, 1)?;
+        api.send_message(format_args!(
+            "@{giver} gave props to @{dj}! @{dj} now has {karma} karma."
+        ));
+        Ok(())
+    }
+
+    fn show_karma(&mut self, api: Api, invocation: &Invocation) -> Result<()> {
+        let db = api.connection();
+        let entry = match invocation.arguments.first() {
+            Some(username) => {
+                let username = username.trim_start_matches('@');
+                let online = api
+                    .state()
+                    .users
+                    .iter()
+                    .find(|user| user.username.eq_ignore_ascii_case(username))
+                    .map(|user| (user.id.clone(), user.username.clone()));
+                match online {
+                    Some((user_id, username)) => Some((username, self.get_karma(&db, &user_id)?)),
+                    None => self.find_by_username(&db, username)?,
+                }
+            }
+            None => {
+                let user_id = &invocation.message.user_id;
+                let username = api.username(user_id)?;
+                Some((username, self.get_karma(&db, user_id)?))
+            }
+        };
+
+        match entry {
+            Some((username, karma)) => {
+                api.send_message(format_args!("@{username} has {karma} karma."))
+            }
+            None => api.send_message(format_args!(
+                "I don't know {}.",
+                invocation.arguments.join(" ")
+            )),
+        }
+        Ok(())
+    }
+
+    fn show_top(&mut self, api: Api) -> Result<()> {
+        let db = api.connection();
+        let top = self.top(&db, TOP_IN_CHAT)?;
+        if top.is_empty() {
+            api.send_message("Nobody has any karma yet.");
+            return Ok(());
+        }
+
+        let list = top
+            .iter()
+            .enumerate()
+            .map(|(index, (username, karma))| format!("{}. {username} ({karma})", index + 1))
+            .collect::<Vec<_>>()
+            .join(", ");
+        api.send_message(format_args!("Top karma: {list}"));
+
+        let page = self.render_leaderboard(&db)?;
+        let url = api.publish("karma.html", &page)?;
+        api.send_message(url);
+        Ok(())
+    }
+
+    fn render_leaderboard(&self, db: &Connection) -> Result<String> {
+        let mut trs = String::new();
+        for (index, (username, karma)) in self.top(db, 100)?.into_iter().enumerate() {
+            write!(
+                &mut trs,
+                r#"
+                <tr>
+                  <td>{rank}</td>
+                  <td>{username}</td>
+                  <td>{karma}</td>
+                </tr>
+                "#,
+                rank = index + 1,
+                username = html_escape::encode_text(&username),
+            )?;
+        }
+
+        let body = format!(
+            r#"
+            <body>
+              <table>
+                <thead><tr>
+                  <th>#</th>
+                  <th>Name</th>
+                  <th>Karma</th>
+                </tr></thead>
+                <tbody>{trs}</tbody>
+              </table>
+            </body>
+        "#
+        );
+
+        render_page(&body)
+    }
+
+    /// Award karma to the DJ of the play that just ended, based on its votes. `next` is the play
+    /// that started after it, if any.
+    fn award_dj(&self, api: Api, history_id: &str, next: Option<&str>) -> Result<()> {
+        let history = api.http.history_iter(HistoryOptions::default());
+        let Some(entry) = find_ended(history, history_id, next)? else {
+            return Ok(());
+        };
+
+        // Don't count the DJ's own votes.
+        let dj_id = &entry.user_id;
+        let upvotes = entry.upvotes.iter().filter(|id| *id != dj_id).count() as i64;
+        let favorites = entry.favorites.iter().filter(|id| *id != dj_id).count() as i64;
+        let amount = upvotes * UPVOTE_KARMA + favorites * FAVORITE_KARMA;
+        if amount == 0 {
+            return Ok(());
+        }
+
+        let username = api.username(dj_id)?;
+        let karma = self.add_karma(&api.connection(), dj_id, &username, amount)?;
+        log::info!("awarded {amount} karma to {username} for {history_id}, now at {karma}");
+        Ok(())
+    }
+}
+
+impl Handler for Karma {
+    fn name(&self) -> &'static str {
+        "karma"
+    }
+
+    fn commands(&self) -> &'static [Command] {
+        const COMMANDS: &[Command] = &[
+            Command::new("props", "Give karma to the current DJ."),
+            Command::new("karma", "Show how much karma a user has.")
+                .arguments(&[Argument::optional("user")]),
+            Command::new(
+                "karmatop",
+                "Show the users with the most karma, and a link to the full leaderboard.",
+            ),
+        ];
+        COMMANDS
+    }
+
+    fn handle_command(&mut self, api: Api, invocation: &Invocation) -> Result<()> {
+        match invocation.command.name {
+            "props" => self.give_props(api, invocation),
+            "karma" => self.show_karma(api, invocation),
+            "karmatop" => self.show_top(api),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle(&mut self, api: Api, message: &MessageType) -> Result<()> {
+        let ended = match message {
+            MessageType::Advance(advance) => {
+                self.current_history_id.replace(advance.history_id.clone())
+            }
+            MessageType::BoothEmpty => self.current_history_id.take(),
+            MessageType::Reconnected(now) => {
+                self.current_history_id = now.booth.as_ref().map(|booth| booth.history_id.clone());
+                None
+            }
+            _ => None,
+        };
+
+        match ended {
+            Some(history_id) => self.award_dj(api, &history_id, self.current_history_id.as_deref()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_ended, Karma, PROPS_COOLDOWN};
+    use crate::api::uwave::{BaseMedia, HistoryEntry, NowState};
+    use crate::migrations::test_db;
+
+    #[test]
+    fn karma_store() -> anyhow::Result<()> {
+        let db = test_db()?;
+        let karma = Karma::new(&NowState::default());
+
+        assert_eq!(karma.add_karma(&db, "a", "Alice", 3)?, 3);
+        assert_eq!(karma.add_karma(&db, "b", "Bob", 5)?, 5);
+        assert_eq!(karma.add_karma(&db, "a", "Alicia", 4)?, 7);
+        assert_eq!(
+            karma.top(&db, 5)?,
+            [("Alicia".to_string(), 7), ("Bob".to_string(), 5)]
+        );
+        assert_eq!(
+            karma.find_by_username(&db, "bob")?,
+            Some(("Bob".to_string(), 5))
+        );
+
+        assert_eq!(karma.props_cooldown(&db, "a", 1000)?, None);
+        karma.record_props(&db, "a", "b", 1000)?;
+        assert_eq!(
+            karma.props_cooldown(&db, "a", 1060)?,
+            Some(PROPS_COOLDOWN - 60)
+        );
+        assert_eq!(karma.props_cooldown(&db, "a", 1000 + PROPS_COOLDOWN)?, None);
+        assert_eq!(karma.props_cooldown(&db, "b", 1060)?, None);
+        Ok(())
+    }
+
+    fn history(ids: &[&str]) -> impl Iterator<Item = anyhow::Result<HistoryEntry<BaseMedia>>> {
+        let entries: Vec<_> = ids
+            .iter()
+            .map(|id| {
+                Ok(serde_json::from_value(serde_json::json!({
+                    "_id": id,
+                    "user": "dj",
+                    "media": {
+                        "media": {
+                            "_id": "m1",
+                            "sourceType": "youtube",
+                            "sourceID": "D1PvIWdJ8xo",
+                            "artist": "IU",
+                            "title": "Blueming",
+                            "duration": 217,
+                        },
+                        "artist": "IU",
+                        "title": "Blueming",
+                        "start": 0,
+                        "end": 217,
+                    },
+                    "playedAt": "2026-10-17T12:00:00Z",
+                    "upvotes": [],
+                    "downvotes": [],
+                    "favorites": [],
+                }))?)
+            })
+            .collect();
+        entries.into_iter()
+    }
+
+    #[test]
+    fn ended_play() -> anyhow::Result<()> {
+        let found = |history_id, next| -> anyhow::Result<Option<String>> {
+            let entry = find_ended(history(&["e", "d", "c"]), history_id, next)?;
+            Ok(entry.map(|entry| entry.history_id))
+        };
+        // More plays may have started since.
+        assert_eq!(found("c", Some("d"))?.as_deref(), Some("c"));
+        assert_eq!(found("d", Some("e"))?.as_deref(), Some("d"));
+        assert_eq!(found("e", None)?.as_deref(), Some("e"));
+        // Plays were missed, for example while the module was disabled.
+        assert_eq!(found("c", Some("e"))?, None);
+        assert_eq!(found("d", None)?, None);
+        assert_eq!(found("b", Some("c"))?, None);
+        Ok(())
+    }
+}
@@ -1,11 +1,16 @@
 mod emotes;
 mod exit;
 mod historyskip;
+mod karma;
+mod module;
+mod page;
 mod skiplist;
 mod version;
 
 pub use emotes::*;
 pub use exit::*;
 pub use historyskip::*;
+pub use karma::*;
+pub use module::*;
 pub use skiplist::*;
 pub use version::*;
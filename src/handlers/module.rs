@@ -0,0 +1,167 @@
+use crate::command::{Argument, Command, Invocation};
+use crate::handler::{Api, Handler};
+use crate::settings;
+use anyhow::{bail, Result};
+use rusqlite::Connection;
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+const SETTINGS_NAMESPACE: &str = "modules";
+/// Handlers that can not be disabled, so moderators can not lock themselves out.
+const ESSENTIAL: &[&str] = &["exit"];
+
+/// Which handlers are enabled. Shared between the `!module` commands and the handler loop.
+#[derive(Debug, Clone)]
+pub struct Modules {
+    names: Arc<[&'static str]>,
+    disabled: Arc<RwLock<HashSet<String>>>,
+}
+
+impl Modules {
+    /// Track the handlers with these names, disabling the ones that were disabled before.
+    pub fn load(db: &Connection, names: Vec<&'static str>) -> Result<Self> {
+        let disabled = settings::load(db, SETTINGS_NAMESPACE)?
+            .into_iter()
+            .filter(|(name, enabled)| enabled == "false" && !ESSENTIAL.contains(&name.as_str()))
+            .map(|(name, _)| name)
+            .collect();
+
+        Ok(Self {
+            names: names.into(),
+            disabled: Arc::new(RwLock::new(disabled)),
+        })
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        !self.disabled.read().unwrap().contains(name)
+    }
+
+    /// Enable or disable a handler, and remember it across restarts.
+    pub fn set_enabled(&self, db: &Connection, name: &str, enabled: bool) -> Result<()> {
+        if !self.names.contains(&name) {
+            bail!(
+                "Unknown module {name}. Available modules: {}",
+                self.names.join(", ")
+            );
+        }
+        if !enabled && ESSENTIAL.contains(&name) {
+            bail!("The {name} module can not be disabled.");
+        }
+
+        settings::store(db, SETTINGS_NAMESPACE, name, &enabled.to_string())?;
+        let mut disabled = self.disabled.write().unwrap();
+        if enabled {
+            disabled.remove(name);
+        } else {
+            disabled.insert(name.to_string());
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> String {
+        self.names
+            .iter()
+            .map(|name| {
+                if self.is_enabled(name) {
+                    name.to_string()
+                } else {
+                    format!("{name} (disabled)")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Commands to enable and disable the other handlers.
+#[derive(Debug)]
+pub struct ModuleCommands {
+    modules: Modules,
+}
+
+impl ModuleCommands {
+    pub fn new(modules: Modules) -> Self {
+        Self { modules }
+    }
+}
+
+impl Handler for ModuleCommands {
+    fn name(&self) -> &'static str {
+        "module"
+    }
+
+    fn commands(&self) -> &'static [Command] {
+        const COMMANDS: &[Command] = &[
+            Command::new("module list", "List the modules and whether they are enabled."),
+            Command::new("module enable", "Enable a module.")
+                .arguments(&[Argument::required("module")])
+                .role("moderator"),
+            Command::new(
+                "module disable",
+                "Disable a module. It ignores all chat commands and events until it is enabled again.",
+            )
+            .arguments(&[Argument::required("module")])
+            .role("moderator"),
+        ];
+        COMMANDS
+    }
+
+    fn handle_command(&mut self, api: Api, invocation: &Invocation) -> Result<()> {
+        match (invocation.command.name, invocation.arguments) {
+            ("module list", _) => {
+                api.send_message(format_args!("Modules: {}", self.modules.list()));
+            }
+            ("module enable", [name]) => {
+                self.modules.set_enabled(&api.connection(), name, true)?;
+                api.send_message(format_args!("Enabled the {name} module."));
+            }
+            ("module disable", [name]) => {
+                self.modules.set_enabled(&api.connection(), name, false)?;
+                api.send_message(format_args!("Disabled the {name} module."));
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Modules, SETTINGS_NAMESPACE};
+    use crate::migrations::test_db;
+    use crate::settings;
+
+    #[test]
+    fn persist_enabled() -> anyhow::Result<()> {
+        let db = test_db()?;
+
+        let modules = Modules::load(&db, vec!["emotes", "historyskip"])?;
+        assert!(modules.is_enabled("historyskip"));
+        modules.set_enabled(&db, "historyskip", false)?;
+        assert!(!modules.is_enabled("historyskip"));
+        assert!(modules.set_enabled(&db, "dance", false).is_err());
+        assert_eq!(modules.list(), "emotes, historyskip (disabled)");
+
+        let modules = Modules::load(&db, vec!["emotes", "historyskip"])?;
+        assert!(!modules.is_enabled("historyskip"));
+        modules.set_enabled(&db, "historyskip", true)?;
+        let modules = Modules::load(&db, vec!["emotes", "historyskip"])?;
+        assert!(modules.is_enabled("historyskip"));
+        Ok(())
+    }
+
+    #[test]
+    fn essential_modules() -> anyhow::Result<()> {
+        let db = test_db()?;
+        let modules = Modules::load(&db, vec!["emotes", "exit"])?;
+        assert!(modules.set_enabled(&db, "exit", false).is_err());
+        assert!(modules.is_enabled("exit"));
+        modules.set_enabled(&db, "exit", true)?;
+
+        // Essential modules that were disabled before are enabled again.
+        settings::store(&db, SETTINGS_NAMESPACE, "exit", "false")?;
+        let modules = Modules::load(&db, vec!["emotes", "exit"])?;
+        assert!(modules.is_enabled("exit"));
+        Ok(())
+    }
+}
@@ -0,0 +1,27 @@
+/// Wrap a page body into a minified HTML document with the shared SekshiBot page style.
+pub fn render_page(body: &str) -> anyhow::Result<String> {
+    let body = minify_html::minify(
+        body.as_bytes(),
+        &minify_html::Cfg {
+            minify_css: true,
+            minify_js: true,
+            ..Default::default()
+        },
+    );
+
+    let html = html_index::new()
+        .raw_body(std::str::from_utf8(&body)?)
+        .inline_style(
+            r#"
+            body { margin: 1rem 4rem; background: #333; color: #f4f4f4; font-family: sans-serif; }
+            table { border-collapse: collapse; border-spacing: 0; margin: auto; }
+            tbody > tr:nth-child(2n+1) { background-color: #0000001a; }
+            th, td { padding: .5rem 1rem; }
+            th { text-transform: uppercase; }
+            a { text-decoration: none; color: #ffa3d7; }
+            a:hover { text-decoration: underline; }
+        "#,
+        );
+
+    Ok(html.build())
+}
@@ -1,12 +1,14 @@
-use crate::api::uwave::SkipOptions;
-use crate::handler::{AdvanceMessage, Api, ChatCommand, ChatMessage, Handler, MessageType};
+use crate::api::uwave::{BaseMedia, SkipOptions};
+use crate::command::{Argument, Command, Invocation};
+use crate::handler::{AdvanceMessage, Api, Handler, MessageType};
 use rusqlite::{params, Connection, OptionalExtension as _};
 use serde::{Deserialize, Serialize};
 use std::fmt::{self, Display, Formatter};
 use std::str::FromStr;
 
+/// A media ID in `sourcetype:id` format.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct Media {
+pub struct Media {
     source_type: String,
     source_id: String,
 }
@@ -41,23 +43,20 @@ struct SkipEntry {
     reason: String,
 }
 
-fn get_media_from_now(now: &serde_json::Value) -> Option<Media> {
-    let media = now.get("booth")?.get("media")?.get("media")?;
-    Some(Media {
-        source_type: media.get("sourceType")?.as_str()?.to_string(),
-        source_id: media.get("sourceID")?.as_str()?.to_string(),
-    })
+impl From<&BaseMedia> for Media {
+    fn from(media: &BaseMedia) -> Self {
+        Self {
+            source_type: media.source_type.clone(),
+            source_id: media.source_id.clone(),
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct SkipList {
-    current_media: Option<Media>,
-}
+#[derive(Debug, Default)]
+pub struct SkipList;
 impl SkipList {
-    pub fn new(now: &serde_json::Value) -> Self {
-        Self {
-            current_media: get_media_from_now(now),
-        }
+    pub fn new() -> Self {
+        Self
     }
 
     fn add_skip_entry(
@@ -90,23 +89,32 @@ impl SkipList {
         Ok(reason.map(|reason| SkipEntry { reason }))
     }
 
-    fn process_skip(&mut self, api: Api, args: &[String], do_skip: bool) -> anyhow::Result<()> {
-        match args {
+    fn process_skip(
+        &mut self,
+        api: Api,
+        invocation: &Invocation,
+        do_skip: bool,
+    ) -> anyhow::Result<()> {
+        match invocation.arguments {
             [media, reason] => {
                 self.add_skip_entry(&api.connection(), media.parse()?, reason)?;
             }
             [reason] => {
-                if let Some(media) = self.current_media.clone() {
+                let media = api
+                    .state()
+                    .current_media()
+                    .map(|media| Media::from(&media.media));
+                if let Some(media) = media {
                     self.add_skip_entry(&api.connection(), media, reason)?;
                 } else {
-                    api.send_message("usage: !skiplist <media> <reason>");
+                    api.send_message(format_args!(
+                        "Nothing is playing. usage: {}",
+                        invocation.usage()
+                    ));
                     return Ok(());
                 }
             }
-            _ => {
-                api.send_message("usage: !skiplist [media] <reason>");
-                return Ok(());
-            }
+            _ => unreachable!("argument count is checked by the dispatcher"),
         }
 
         if do_skip {
@@ -116,40 +124,8 @@ impl SkipList {
         Ok(())
     }
 
-    fn handle_chat_message(&mut self, api: Api, message: &ChatMessage) -> anyhow::Result<()> {
-        let ChatCommand { command, arguments } = match message.command() {
-            Some(c) => c,
-            None => return Ok(()),
-        };
-
-        match command.as_str() {
-            "skiplist" | "blacklist" => {
-                match arguments.get(1).cloned().as_deref() {
-                    Some("add") => {
-                        self.process_skip(api, &arguments[2..], false)?;
-                    }
-                    Some("skip") => {
-                        self.process_skip(api, &arguments[2..], true)?;
-                    }
-                    Some(_) => {
-                        self.process_skip(api, &arguments[1..], false)?;
-                    }
-                    None => {
-                        api.send_message("usage: !skiplist [media] <reason>");
-                    }
-                }
-
-                Ok(())
-            }
-            _ => Ok(()),
-        }
-    }
-
     fn handle_advance(&mut self, api: Api, message: &AdvanceMessage) -> anyhow::Result<()> {
-        let media = Media {
-            source_type: message.media.media.source_type.clone(),
-            source_id: message.media.media.source_id.clone(),
-        };
+        let media = Media::from(&message.media.media);
 
         if let Some(entry) = self.get_skip_entry(&api.connection(), &media)? {
             api.http.skip(SkipOptions {
@@ -168,9 +144,41 @@ impl SkipList {
 }
 
 impl Handler for SkipList {
+    fn name(&self) -> &'static str {
+        "skiplist"
+    }
+
+    fn commands(&self) -> &'static [Command] {
+        const ARGUMENTS: &[Argument] = &[Argument::optional("media"), Argument::required("reason")];
+        const COMMANDS: &[Command] = &[
+            Command::new(
+                "skiplist add",
+                "Add a song to the autoskip list. [media] is formatted as sourcetype:id, and defaults to the current song.",
+            )
+            .aliases(&["skiplist", "blacklist add", "blacklist"])
+            .arguments(ARGUMENTS)
+            .role("moderator"),
+            Command::new(
+                "skiplist skip",
+                "Add a song to the autoskip list and skip it.",
+            )
+            .aliases(&["blacklist skip"])
+            .arguments(ARGUMENTS)
+            .role("moderator"),
+        ];
+        COMMANDS
+    }
+
+    fn handle_command(&mut self, api: Api, invocation: &Invocation) -> anyhow::Result<()> {
+        match invocation.command.name {
+            "skiplist add" => self.process_skip(api, invocation, false),
+            "skiplist skip" => self.process_skip(api, invocation, true),
+            _ => Ok(()),
+        }
+    }
+
     fn handle(&mut self, api: Api, message: &MessageType) -> anyhow::Result<()> {
         match message {
-            MessageType::ChatMessage(message) => self.handle_chat_message(api, message),
             MessageType::Advance(message) => self.handle_advance(api, message),
             _ => Ok(()),
         }
@@ -1,27 +1,25 @@
-use crate::handler::{Api, ChatCommand, Handler, MessageType};
+use crate::command::{Command, Invocation};
+use crate::handler::{Api, Handler};
 
 #[derive(Debug, Default)]
 pub struct Version;
 
 impl Handler for Version {
-    fn handle(&mut self, api: Api, message: &MessageType) -> anyhow::Result<()> {
-        let message = match message {
-            MessageType::ChatMessage(message) => message,
-            _ => return Ok(()),
-        };
-        let ChatCommand { command, .. } = if let Some(c) = message.command() {
-            c
-        } else {
-            return Ok(());
-        };
+    fn name(&self) -> &'static str {
+        "version"
+    }
+
+    fn commands(&self) -> &'static [Command] {
+        const COMMANDS: &[Command] = &[Command::new("version", "Show the running bot version.")];
+        COMMANDS
+    }
 
-        if command.as_str() == "version" {
-            api.send_message(format_args!(
-                "Running {} v{}",
-                env!("CARGO_PKG_NAME"),
-                env!("CARGO_PKG_VERSION")
-            ));
-        }
+    fn handle_command(&mut self, api: Api, _invocation: &Invocation) -> anyhow::Result<()> {
+        api.send_message(format_args!(
+            "Running {} v{}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        ));
 
         Ok(())
     }
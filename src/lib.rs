@@ -1,43 +1,55 @@
 #![recursion_limit = "512"]
+mod command;
+mod config;
 mod handler;
 mod handlers;
 mod migrations;
+mod outbox;
+mod publisher;
+mod roles;
+mod settings;
+mod similarity;
+mod workers;
 mod api {
+    #[cfg(test)]
+    pub mod mock;
     pub mod neocities;
     pub mod uwave;
 }
 
-use crate::api::uwave::HttpApi;
+use crate::api::uwave::{HttpApi, NowState};
+use crate::command::Commands;
 use crate::handler::Handler;
+use crate::outbox::Outbox;
+use crate::roles::RoleCache;
+use crate::workers::WorkerPool;
+use flume::{Receiver, Sender};
+use polling::{Event, Poller};
 use r2d2_sqlite::SqliteConnectionManager;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use signal_hook::low_level::signal_name;
 use std::net::TcpStream;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use tungstenite::stream::MaybeTlsStream;
 use tungstenite::Message;
-use ureq::{Agent, AgentBuilder};
+use ureq::AgentBuilder;
 
 // Expose so the CLI can use a special exit code
 pub use crate::api::uwave::UnauthorizedError;
+pub use crate::config::Config;
 
 type WebSocket = tungstenite::WebSocket<MaybeTlsStream<TcpStream>>;
 
-#[derive(Debug, Clone)]
-pub struct ConnectionOptions {
-    pub api_url: String,
-    pub socket_url: String,
-    pub email: String,
-    pub password: String,
-}
-
 pub struct SekshiBot {
     pool: r2d2::Pool<SqliteConnectionManager>,
-    client: Agent,
+    http: HttpApi,
     socket: WebSocket,
-    api_url: String,
-    api_auth: String,
+    state: NowState,
     handlers: Vec<Box<dyn Handler + Send>>,
+    config: Config,
 }
 
 fn connect_ws(url: &str) -> anyhow::Result<WebSocket> {
@@ -63,64 +75,294 @@ fn connect_ws(url: &str) -> anyhow::Result<WebSocket> {
     }
 }
 
+/// Load the current state and open a socket connection authenticated with its socket token.
+fn connect_socket(http: &HttpApi, socket_url: &str) -> anyhow::Result<(WebSocket, NowState)> {
+    log::info!("loading state...");
+    let mut now = http.now()?;
+    // `now` is also available to guests, so an expired session does not cause a 401 here.
+    if now.socket_token.is_none() {
+        http.sign_in()?;
+        now = http.now()?;
+    }
+
+    let Some(socket_token) = now.socket_token.clone() else {
+        return Err(UnauthorizedError.into());
+    };
+
+    log::info!("connecting to {socket_url}...");
+    let mut socket = connect_ws(socket_url)?;
+    socket.write_message(Message::Text(socket_token))?;
+
+    Ok((socket, now))
+}
+
+/// Exponential delay between reconnection attempts. It only starts over once a connection stayed
+/// up for a while, so a server that drops connections right away is not hammered.
+#[derive(Debug, Default)]
+struct Backoff {
+    attempt: u32,
+    /// When the current connection was established.
+    connected_at: Option<Instant>,
+}
+
+impl Backoff {
+    const INITIAL: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(60);
+    /// How long a connection must stay up to reset the delay.
+    const STABLE: Duration = Duration::from_secs(30);
+
+    fn connected(&mut self, now: Instant) {
+        self.connected_at = Some(now);
+    }
+
+    fn next_delay(&mut self, now: Instant) -> Duration {
+        let stable = self.connected_at.take().is_some_and(|connected_at| {
+            now.saturating_duration_since(connected_at) >= Self::STABLE
+        });
+        if stable {
+            self.attempt = 0;
+        }
+        let delay = Self::INITIAL
+            .checked_mul(1 << self.attempt.min(16))
+            .map_or(Self::MAX, |delay| delay.min(Self::MAX));
+        self.attempt = self.attempt.saturating_add(1);
+        delay
+    }
+}
+
+/// Why the socket loop stopped.
+enum SocketEnd {
+    /// The bot is shutting down.
+    Exit,
+    /// The connection was lost and should be reestablished.
+    Disconnected,
+}
+
+/// Poller key for socket readability.
+const SOCKET_KEY: usize = 0;
+
+fn socket_fd(socket: &WebSocket) -> RawFd {
+    match socket.get_ref() {
+        MaybeTlsStream::Plain(stream) => stream.as_raw_fd(),
+        MaybeTlsStream::NativeTls(stream) => stream.get_ref().as_raw_fd(),
+        _ => unreachable!("only plain and native-tls streams are used"),
+    }
+}
+
+/// How long to keep sending rate limited chat messages before logging out.
+const EXIT_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Send the chat messages that the rate limit allows.
+fn send_chat(socket: &mut WebSocket, outbox: &mut Outbox) -> anyhow::Result<()> {
+    while let Some(message) = outbox.pop(Instant::now()) {
+        log::info!("sending chat message: {message}");
+        let send_chat = serde_json::json!({
+            "command": "sendChat",
+            "data": message,
+        });
+        socket.write_message(Message::Text(send_chat.to_string()))?;
+    }
+    Ok(())
+}
+
+fn process_socket(
+    socket: &mut WebSocket,
+    poller: &Poller,
+    outbox: &mut Outbox,
+    api_receiver: &Receiver<handler::ApiMessage>,
+    received_message_sender: &Sender<handler::MessageType>,
+) -> anyhow::Result<SocketEnd> {
+    let fd = socket_fd(socket);
+    poller.add(fd, Event::readable(SOCKET_KEY))?;
+    let result = socket_loop(
+        socket,
+        fd,
+        poller,
+        outbox,
+        api_receiver,
+        received_message_sender,
+    );
+    let _ = poller.delete(fd);
+    result
+}
+
+/// Forward socket messages and send outgoing messages, sleeping until the socket is readable, an
+/// outgoing message is queued, or the rate limit allows sending more chat messages.
+fn socket_loop(
+    socket: &mut WebSocket,
+    fd: RawFd,
+    poller: &Poller,
+    outbox: &mut Outbox,
+    api_receiver: &Receiver<handler::ApiMessage>,
+    received_message_sender: &Sender<handler::MessageType>,
+) -> anyhow::Result<SocketEnd> {
+    let mut events = vec![];
+    loop {
+        // Process all queued messages.
+        loop {
+            let message = socket.read_message();
+            let message = match message {
+                Ok(Message::Text(message)) => {
+                    if message == "-" {
+                        continue;
+                    }
+                    Some(message)
+                }
+                Ok(Message::Close(_)) => {
+                    log::info!("connection ended");
+                    return Ok(SocketEnd::Disconnected);
+                }
+                Ok(_) => None,
+                Err(tungstenite::Error::Io(io_err))
+                    if io_err.kind() == std::io::ErrorKind::WouldBlock =>
+                {
+                    break
+                }
+                Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                    log::info!("connection closed");
+                    return Ok(SocketEnd::Disconnected);
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            if let Some(message) = message {
+                let message: handler::Message = match serde_json::from_str(&message) {
+                    Ok(message) => message,
+                    Err(err) => {
+                        log::warn!("could not parse socket message {message:?}: {err}");
+                        continue;
+                    }
+                };
+
+                if let Some(message_type) = message.into_message_type() {
+                    let _ = received_message_sender.send(message_type);
+                }
+            }
+        }
+
+        loop {
+            match api_receiver.try_recv() {
+                Err(flume::TryRecvError::Empty) => break,
+                Ok(handler::ApiMessage::SendChat(message)) => {
+                    outbox.push(&message, Instant::now());
+                }
+                Ok(handler::ApiMessage::ChatConfig(config)) => outbox.set_config(config),
+                Ok(handler::ApiMessage::Exit) | Err(flume::TryRecvError::Disconnected) => {
+                    let deadline = Instant::now() + EXIT_FLUSH_TIMEOUT;
+                    send_chat(socket, outbox)?;
+                    while let Some(next) = outbox.next_send(Instant::now()) {
+                        if next > deadline {
+                            break;
+                        }
+                        std::thread::sleep(next.saturating_duration_since(Instant::now()));
+                        send_chat(socket, outbox)?;
+                    }
+                    if outbox.len() > 0 {
+                        log::warn!("dropping {} rate limited chat messages", outbox.len());
+                    }
+                    log::info!("logging out");
+                    let logout = serde_json::json!({ "command": "logout" });
+                    socket.write_message(Message::Text(logout.to_string()))?;
+                    socket.close(None)?;
+                    return Ok(SocketEnd::Exit);
+                }
+            }
+        }
+
+        send_chat(socket, outbox)?;
+
+        // Sending on the API channel notifies the poller, so this also wakes up for messages that
+        // were queued after the loop above.
+        let now = Instant::now();
+        let timeout = outbox
+            .next_send(now)
+            .map(|next| next.saturating_duration_since(now));
+        events.clear();
+        poller.wait(&mut events, timeout)?;
+        if events.iter().any(|event| event.key == SOCKET_KEY) {
+            poller.modify(fd, Event::readable(SOCKET_KEY))?;
+        }
+    }
+}
+
+/// Wait until `deadline` while staying responsive to exit requests. Chat messages are queued
+/// until the connection is back.
+///
+/// Returns `false` if the bot should exit instead of reconnecting.
+fn wait_for_reconnect(
+    deadline: Instant,
+    outbox: &mut Outbox,
+    api_receiver: &Receiver<handler::ApiMessage>,
+) -> bool {
+    loop {
+        match api_receiver.recv_deadline(deadline) {
+            Err(flume::RecvTimeoutError::Timeout) => return true,
+            Ok(handler::ApiMessage::SendChat(message)) => {
+                log::info!("queueing chat message while disconnected: {message}");
+                outbox.push(&message, Instant::now());
+            }
+            Ok(handler::ApiMessage::ChatConfig(config)) => outbox.set_config(config),
+            Ok(handler::ApiMessage::Exit) | Err(flume::RecvTimeoutError::Disconnected) => {
+                return false
+            }
+        }
+    }
+}
+
+/// What the handler thread does next.
+enum HandlerEvent {
+    Message(handler::MessageType),
+    Reload,
+    /// Shut down, then tell the socket thread to log out.
+    Exit,
+    /// The socket thread stopped.
+    Stop,
+}
+
 impl SekshiBot {
-    pub fn connect(options: ConnectionOptions) -> anyhow::Result<Self> {
-        let url = |endpoint: &str| format!("{}/{}", options.api_url, endpoint);
-        let client = AgentBuilder::new().build();
+    pub fn connect(config: Config) -> anyhow::Result<Self> {
+        config.validate()?;
+        for warning in config.warnings() {
+            log::warn!("{warning}");
+        }
 
-        log::info!("signing in...");
-        let login = client
-            .post(&url("auth/login"))
-            .send_json(serde_json::json!({
-                "email": options.email,
-                "password": options.password,
-            }))?
-            .into_json::<serde_json::Value>()?;
-
-        let jwt = if let Some(jwt) = login["meta"]["jwt"].as_str() {
-            jwt.to_string()
-        } else {
-            anyhow::bail!("no jwt found")
-        };
-        let api_auth = format!("JWT {jwt}");
-
-        log::info!("loading state...");
-        let now = client
-            .get(&url("now"))
-            .set("Authorization", &api_auth)
-            .call()?
-            .into_json::<serde_json::Value>()?;
-
-        let socket_token = match &now["socketToken"] {
-            serde_json::Value::Null => None,
-            serde_json::Value::String(token) => Some(token),
-            _ => anyhow::bail!("unexpected socket token type"),
-        };
+        let client = AgentBuilder::new().build();
+        let http = HttpApi::login(
+            client,
+            config.api_url.clone(),
+            config.email.clone(),
+            config.password.clone(),
+        )?;
 
-        log::info!("connecting to {}...", options.socket_url);
-        let mut socket = connect_ws(&options.socket_url)?;
-        let manager = SqliteConnectionManager::file("sekshi.sqlite");
+        let (socket, now) = connect_socket(&http, &config.socket_url)?;
+        let manager = SqliteConnectionManager::file(&config.database);
         let pool = r2d2::Pool::new(manager).unwrap();
         migrations::MIGRATIONS.to_latest(&mut pool.get().unwrap())?;
 
-        if let Some(token) = socket_token {
-            socket.write_message(Message::Text(token.to_string()))?;
-        }
-
         let mut bot = Self {
             pool,
-            client,
+            http,
             socket,
-            api_url: options.api_url,
-            api_auth,
+            state: now,
             handlers: vec![],
+            config: config.clone(),
         };
 
-        bot.add_handler(handlers::Emotes);
-        bot.add_handler(handlers::Exit);
-        bot.add_handler(handlers::SkipList::new(&now));
-        bot.add_handler(handlers::HistorySkip::new());
-        bot.add_handler(handlers::Version);
+        for name in &config.handlers {
+            match name.as_str() {
+                "emotes" => bot.add_handler(handlers::Emotes::new(config.emotes.clone())),
+                "exit" => bot.add_handler(handlers::Exit),
+                "skiplist" => bot.add_handler(handlers::SkipList::new()),
+                "historyskip" => {
+                    let history_skip =
+                        handlers::HistorySkip::new(config.historyskip.clone(), &*bot.pool.get()?)?;
+                    bot.add_handler(history_skip);
+                }
+                "karma" => bot.add_handler(handlers::Karma::new(&bot.state)),
+                "version" => bot.add_handler(handlers::Version),
+                _ => unreachable!("handler names are validated"),
+            }
+        }
 
         Ok(bot)
     }
@@ -130,118 +372,161 @@ impl SekshiBot {
     }
 
     pub fn run(self) -> anyhow::Result<()> {
-        let exit_flag = Arc::new(AtomicBool::new(false));
-        signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&exit_flag))?;
-
+        let poller = Arc::new(Poller::new()?);
         let (api_sender, api_receiver) = flume::bounded(10);
+        let (exit_sender, exit_receiver) = flume::unbounded();
+        let api_sender = handler::ApiSender::new(api_sender, exit_sender, Arc::clone(&poller));
+
+        let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
+        let signals_handle = signals.handle();
+        let signal_api_sender = api_sender.clone();
+        let (reload_sender, reload_receiver) = flume::unbounded();
+        std::thread::spawn(move || {
+            for signal in signals.forever() {
+                log::info!("received {}", signal_name(signal).unwrap_or("signal"));
+                if signal == SIGHUP {
+                    let _ = reload_sender.send(());
+                } else {
+                    signal_api_sender.exit();
+                }
+            }
+        });
         let (received_message_sender, received_message_receiver) = flume::bounded(10);
 
         let pool = self.pool;
         let mut socket = self.socket;
+        let socket_url = self.config.socket_url.clone();
         let mut handlers = self.handlers;
-        let http_api = HttpApi::new(self.client, self.api_url, self.api_auth);
-
-        let socket_exit_flag = Arc::clone(&exit_flag);
+        let modules = handlers::Modules::load(
+            &*pool.get()?,
+            handlers.iter().map(|handler| handler.name()).collect(),
+        )?;
+        handlers.push(Box::new(handlers::ModuleCommands::new(modules.clone())));
+        let mut config = self.config;
+        let commands = Commands::new(&handlers, &config.command_prefix);
+        let mut publisher = Arc::new(config.publisher.clone());
+        let http_api = self.http;
+        let state = Arc::new(RwLock::new(self.state));
+        let roles = RoleCache::default();
+
+        let socket_http_api = http_api.clone();
+        let mut outbox = Outbox::new(config.chat.clone());
         let socket_thread = std::thread::spawn(move || {
-            while !socket_exit_flag.load(Ordering::Relaxed) {
-                // Process all queued messages.
-                loop {
-                    let message = socket.read_message();
-                    let message = match message {
-                        Ok(Message::Text(message)) => {
-                            if message == "-" {
-                                continue;
-                            }
-                            Some(message)
-                        }
-                        Ok(Message::Close(_)) => {
-                            log::info!("connection ended");
-                            break;
-                        }
-                        Ok(_) => None,
-                        Err(tungstenite::Error::Io(io_err))
-                            if io_err.kind() == std::io::ErrorKind::WouldBlock =>
-                        {
-                            break
-                        }
-                        Err(tungstenite::Error::ConnectionClosed) => {
-                            log::info!("connection closed");
-                            socket_exit_flag.store(true, Ordering::Relaxed);
-                            return Ok(());
-                        }
-                        Err(err) => {
-                            todo!("handle error {:?}", err)
-                        }
-                    };
-
-                    if let Some(message) = message {
-                        let message: handler::Message = serde_json::from_str(&message).unwrap();
-
-                        if let Some(message_type) = message.into_message_type() {
-                            let _ = received_message_sender.send(message_type);
-                        }
-                    }
+            let mut backoff = Backoff::default();
+
+            loop {
+                backoff.connected(Instant::now());
+                match process_socket(
+                    &mut socket,
+                    &poller,
+                    &mut outbox,
+                    &api_receiver,
+                    &received_message_sender,
+                ) {
+                    Ok(SocketEnd::Exit) => break,
+                    Ok(SocketEnd::Disconnected) => (),
+                    Err(err) => log::warn!("socket error: {err}"),
                 }
 
-                match api_receiver.recv_timeout(Duration::from_millis(16)) {
-                    Err(flume::RecvTimeoutError::Timeout) => continue,
-                    Ok(handler::ApiMessage::SendChat(message)) => {
-                        log::info!("sending chat message: {message}");
-                        let send_chat = serde_json::json!({
-                            "command": "sendChat",
-                            "data": message,
-                        });
-                        socket.write_message(Message::Text(send_chat.to_string()))?;
+                socket = loop {
+                    let delay = backoff.next_delay(Instant::now());
+                    log::info!("reconnecting in {delay:?}...");
+                    if !wait_for_reconnect(Instant::now() + delay, &mut outbox, &api_receiver) {
+                        return Ok(());
                     }
-                    Ok(handler::ApiMessage::Exit) | Err(_) => {
-                        log::info!("logging out");
-                        let logout = serde_json::json!({ "command": "logout" });
-                        socket.write_message(Message::Text(logout.to_string()))?;
-                        socket.close(None)?;
-                        break;
+
+                    match connect_socket(&socket_http_api, &socket_url) {
+                        Ok((socket, now)) => {
+                            let _ = received_message_sender
+                                .send(handler::MessageType::Reconnected(Box::new(now)));
+                            break socket;
+                        }
+                        Err(err) if err.is::<UnauthorizedError>() => {
+                            return Err(err);
+                        }
+                        Err(err) => log::warn!("could not reconnect: {err}"),
                     }
-                }
+                };
             }
 
             anyhow::Result::<()>::Ok(())
         });
 
+        let (fatal_sender, fatal_receiver) = flume::unbounded();
+        let workers = WorkerPool::new(
+            handlers,
+            commands,
+            modules,
+            config.workers.clone(),
+            fatal_sender,
+        );
+
         let (handler_end_sender, end_receiver) = flume::bounded(1);
-        let handler_exit_flag = Arc::clone(&exit_flag);
         let handler_thread = std::thread::spawn(move || {
-            let mut retval = Ok(());
-
-            'outer: while !handler_exit_flag.load(Ordering::Relaxed) {
-                let message =
-                    match received_message_receiver.recv_timeout(Duration::from_millis(16)) {
-                        Ok(message) => message,
-                        Err(flume::RecvTimeoutError::Timeout) => continue,
-                        Err(err) => {
-                            log::warn!("handler exiting because: {:?}", err);
-                            break;
+            let make_api = |publisher: &Arc<_>| {
+                handler::Api::new(
+                    api_sender.clone(),
+                    pool.clone(),
+                    Arc::clone(&state),
+                    roles.clone(),
+                    http_api.clone(),
+                    Arc::clone(publisher),
+                )
+            };
+
+            // Ends when exiting or when the socket thread stops.
+            let exiting = loop {
+                let event = flume::Selector::new()
+                    .recv(&received_message_receiver, |message| {
+                        message.map_or(HandlerEvent::Stop, HandlerEvent::Message)
+                    })
+                    .recv(&reload_receiver, |reload| {
+                        reload.map_or(HandlerEvent::Stop, |()| HandlerEvent::Reload)
+                    })
+                    .recv(&exit_receiver, |_| HandlerEvent::Exit)
+                    .wait();
+
+                match event {
+                    HandlerEvent::Message(mut message) => {
+                        if let handler::MessageType::ChatMessage(chat_message) = &mut message {
+                            chat_message.parse(&config.command_prefix);
                         }
-                    };
-
-                // TODO spawn these onto a threadpool
-                log::info!("handling message {:?}", message);
-                let api = handler::Api::new(api_sender.clone(), pool.clone(), http_api.clone());
-                for handler in handlers.iter_mut() {
-                    match handler.handle(api.clone(), &message) {
-                        Ok(..) => (),
-                        Err(err) => {
-                            // Exit if we are no longer authenticated so the bot can be restarted
-                            if err.is::<UnauthorizedError>() {
-                                api.exit();
-                                retval = Err(err);
-                                break 'outer;
-                            }
+                        state.write().unwrap().apply(&message);
+                        roles.apply(&message);
 
-                            api.send_message(format_args!("Could not handle message: {err}"));
+                        log::info!("handling message {:?}", message);
+                        workers.handle(&make_api(&publisher), message);
+                    }
+                    HandlerEvent::Reload => {
+                        log::info!("reloading configuration");
+                        match config.reload() {
+                            Ok(reloaded) => {
+                                config = reloaded;
+                                if let Ok(level) = config.log_level() {
+                                    log::set_max_level(level);
+                                }
+                                publisher = Arc::new(config.publisher.clone());
+                                api_sender
+                                    .send(handler::ApiMessage::ChatConfig(config.chat.clone()));
+                                workers.reload(&make_api(&publisher), Arc::new(config.clone()));
+                            }
+                            Err(err) => log::error!("could not reload configuration: {err:#}"),
                         }
                     }
+                    HandlerEvent::Exit => break true,
+                    HandlerEvent::Stop => break false,
                 }
-            }
+            };
 
+            // Replies sent while shutting down go out before the socket thread logs out.
+            workers.shutdown(&make_api(&publisher));
+            if exiting {
+                api_sender.send(handler::ApiMessage::Exit);
+            }
+            let retval = match fatal_receiver.try_recv() {
+                Ok(err) => Err(err),
+                Err(_) => Ok(()),
+            };
             handler_end_sender.send(retval).unwrap();
         });
 
@@ -249,9 +534,171 @@ impl SekshiBot {
             .recv(&end_receiver, |err| err)
             .wait()?;
 
+        signals_handle.close();
         socket_thread.join().unwrap()?;
         handler_thread.join().unwrap();
 
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{wait_for_reconnect, Backoff, Config, SekshiBot};
+    use crate::api::mock::{MockUwave, Scenario};
+    use crate::handler::{ApiMessage, ApiSender};
+    use crate::outbox::Outbox;
+    use chrono::Utc;
+    use polling::Poller;
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn media() -> serde_json::Value {
+        json!({
+            "media": {
+                "_id": "m1",
+                "sourceType": "youtube",
+                "sourceID": "D1PvIWdJ8xo",
+                "artist": "IU",
+                "title": "Blueming",
+                "duration": 217,
+            },
+            "artist": "IU",
+            "title": "Blueming",
+            "start": 0,
+            "end": 217,
+        })
+    }
+
+    fn history_entry(id: &str, user_id: &str, minutes_ago: i64) -> serde_json::Value {
+        json!({
+            "_id": id,
+            "user": user_id,
+            "media": media(),
+            "playedAt": (Utc::now() - chrono::Duration::minutes(minutes_ago)).to_rfc3339(),
+            "upvotes": [],
+            "downvotes": [],
+            "favorites": [],
+        })
+    }
+
+    #[test]
+    fn backoff() {
+        let start = Instant::now();
+        let mut backoff = Backoff::default();
+        assert_eq!(backoff.next_delay(start), Duration::from_secs(1));
+        assert_eq!(backoff.next_delay(start), Duration::from_secs(2));
+        assert_eq!(backoff.next_delay(start), Duration::from_secs(4));
+        for _ in 0..20 {
+            backoff.next_delay(start);
+        }
+        assert_eq!(backoff.next_delay(start), Duration::from_secs(60));
+
+        backoff.connected(start);
+        let later = start + Duration::from_secs(30);
+        assert_eq!(backoff.next_delay(later), Duration::from_secs(1));
+        assert_eq!(backoff.next_delay(later), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn backoff_short_connections() {
+        // Connections that are dropped right away do not reset the delay.
+        let start = Instant::now();
+        let mut backoff = Backoff::default();
+        backoff.connected(start);
+        assert_eq!(backoff.next_delay(start), Duration::from_secs(1));
+        backoff.connected(start + Duration::from_secs(1));
+        assert_eq!(
+            backoff.next_delay(start + Duration::from_secs(2)),
+            Duration::from_secs(2)
+        );
+        backoff.connected(start + Duration::from_secs(4));
+        assert_eq!(
+            backoff.next_delay(start + Duration::from_secs(5)),
+            Duration::from_secs(4)
+        );
+    }
+
+    #[test]
+    fn wake_up() -> anyhow::Result<()> {
+        let poller = Arc::new(Poller::new()?);
+        let (sender, receiver) = flume::bounded(10);
+        let sender = ApiSender::new(sender, flume::unbounded().0, Arc::clone(&poller));
+
+        let start = Instant::now();
+        let thread_sender = sender.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            thread_sender.send(ApiMessage::SendChat("hello".into()));
+        });
+        poller.wait(&mut vec![], Some(Duration::from_secs(5)))?;
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(matches!(receiver.try_recv(), Ok(ApiMessage::SendChat(_))));
+
+        // Waiting to reconnect stops early when exiting.
+        let start = Instant::now();
+        let mut outbox = Outbox::new(Default::default());
+        sender.send(ApiMessage::SendChat("hello again".into()));
+        sender.send(ApiMessage::Exit);
+        assert!(!wait_for_reconnect(
+            start + Duration::from_secs(5),
+            &mut outbox,
+            &receiver
+        ));
+        assert_eq!(outbox.len(), 1);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        Ok(())
+    }
+
+    #[test]
+    fn end_to_end() {
+        let server = MockUwave::start(json!([
+            { "_id": "dj", "username": "Someone", "roles": ["user"] },
+            { "_id": "boss", "username": "Boss", "roles": ["manager"] },
+        ]));
+        server.set_history(vec![
+            history_entry("h2", "dj", 0),
+            history_entry("h1", "dj", 10),
+        ]);
+
+        let database =
+            std::env::temp_dir().join(format!("sekshibot-e2e-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&database);
+        let config = Config {
+            api_url: server.api_url().to_string(),
+            socket_url: server.socket_url().to_string(),
+            email: "sekshibot@example.com".into(),
+            password: "hunter2".into(),
+            database: database.to_string_lossy().into_owned(),
+            handlers: vec!["exit".into(), "historyskip".into()],
+            ..Config::default()
+        };
+        let bot = std::thread::spawn(move || SekshiBot::connect(config)?.run());
+
+        let requests = server.run(
+            Scenario::new()
+                .emit(
+                    "advance",
+                    json!({
+                        "historyID": "h2",
+                        "userID": "dj",
+                        "media": media(),
+                        "playedAt": Utc::now().timestamp_millis(),
+                    }),
+                )
+                .expect_request("POST", "/booth/skip")
+                .expect_chat("This song was played 10 minutes ago.")
+                .chat("dj", "!exit")
+                .expect_chat("@Someone You need the manager role to use !exit.")
+                .chat("boss", "!exit"),
+        );
+        assert_eq!(requests[0].body["userID"], "dj");
+        assert_eq!(requests[0].body["reason"], "history");
+        assert_eq!(requests[0].authorization.as_deref(), Some("JWT mock-jwt"));
+
+        bot.join().unwrap().unwrap();
+        assert_eq!(server.chat().len(), 2);
+        let _ = std::fs::remove_file(&database);
+    }
+}
@@ -1,40 +1,69 @@
-use anyhow::{bail, Result};
+use anyhow::Result;
 use gumdrop::{Options, ParsingStyle};
-use sekshibot::{ConnectionOptions, SekshiBot, UnauthorizedError};
+use sekshibot::{Config, SekshiBot, UnauthorizedError};
+use std::path::PathBuf;
 
 ///
 #[derive(Debug, Clone, Options)]
 pub struct Cli {
-    /// HTTP API endpoint of the üWave server to connect to.
-    #[options(required)]
-    pub api_url: String,
-    /// WebSocket API endpoint of the üWave server to connect to.
-    #[options(required)]
-    pub socket_url: String,
+    /// Path to the configuration file.
+    #[options(meta = "PATH")]
+    pub config: Option<PathBuf>,
+    /// HTTP API endpoint of the üWave server. Overrides the configuration file.
+    pub api_url: Option<String>,
+    /// WebSocket API endpoint of the üWave server. Overrides the configuration file.
+    pub socket_url: Option<String>,
     pub help: bool,
+    #[options(command)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Options)]
+pub enum Command {
+    /// Check the configuration for errors and exit.
+    CheckConfig(CheckConfigOptions),
+}
+
+#[derive(Debug, Clone, Options)]
+pub struct CheckConfigOptions {
+    pub help: bool,
+}
+
+fn load_config(args: &Cli) -> Result<Config> {
+    let mut config = Config::load(args.config.as_deref())?;
+    if let Some(api_url) = &args.api_url {
+        config.api_url = api_url.clone();
+    }
+    if let Some(socket_url) = &args.socket_url {
+        config.socket_url = socket_url.clone();
+    }
+    Ok(config)
 }
 
 fn main() -> Result<()> {
-    femme::with_level(log::LevelFilter::Info);
     let args = Cli::parse_args_or_exit(ParsingStyle::AllOptions);
-    log::info!("args: {:?}", args);
+    let config = load_config(&args)?;
 
-    let email = match std::env::var("SEKSHIBOT_EMAIL") {
-        Ok(email) => email,
-        _ => bail!("missing SEKSHIBOT_EMAIL env var"),
-    };
-    let password = match std::env::var("SEKSHIBOT_PASSWORD") {
-        Ok(password) => password,
-        _ => bail!("missing SEKSHIBOT_PASSWORD env var"),
-    };
+    if let Some(Command::CheckConfig(_)) = args.command {
+        for warning in config.warnings() {
+            eprintln!("Warning: {warning}");
+        }
+        let problems = config.problems();
+        if problems.is_empty() {
+            println!("Configuration OK.");
+            return Ok(());
+        }
+        for problem in problems {
+            eprintln!("Error: {problem}");
+        }
+        quit::with_code(1);
+    }
+
+    femme::with_level(config.log_level()?);
+    log::info!("args: {:?}", args);
 
     let result = (|| {
-        let bot = SekshiBot::connect(ConnectionOptions {
-            api_url: args.api_url,
-            socket_url: args.socket_url,
-            email,
-            password,
-        })?;
+        let bot = SekshiBot::connect(config)?;
 
         bot.run()
     })();
@@ -21,9 +21,72 @@ lazy_static! {
             ) STRICT;
         "
         ),
+        M::up(
+            "
+            CREATE TABLE karma (
+                user_id TEXT NOT NULL PRIMARY KEY,
+                username TEXT NOT NULL,
+                karma INTEGER NOT NULL DEFAULT 0
+            ) STRICT;
+            CREATE TABLE karma_props (
+                giver_id TEXT NOT NULL,
+                receiver_id TEXT NOT NULL,
+                given_at INTEGER NOT NULL
+            ) STRICT;
+            CREATE INDEX karma_props_giver ON karma_props (giver_id, given_at);
+        "
+        ),
+        M::up(
+            "
+            CREATE TABLE historyskip_exemptions (
+                kind TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (kind, value)
+            ) STRICT;
+        "
+        ),
+        M::up(
+            "
+            CREATE TABLE settings (
+                namespace TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (namespace, key)
+            ) STRICT;
+        "
+        ),
+        M::up(
+            "
+            CREATE TABLE emote_aliases (
+                alias TEXT NOT NULL PRIMARY KEY,
+                emote TEXT NOT NULL
+            ) STRICT;
+            CREATE INDEX emote_aliases_emote ON emote_aliases (emote);
+        "
+        ),
+        M::up(
+            "
+            ALTER TABLE emotes ADD COLUMN added_by TEXT;
+            ALTER TABLE emotes ADD COLUMN added_at INTEGER;
+            CREATE TABLE emote_uses (
+                emote TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                used_at INTEGER NOT NULL
+            ) STRICT;
+            CREATE INDEX emote_uses_emote ON emote_uses (emote);
+        "
+        ),
     ]);
 }
 
+/// An in-memory database with all migrations applied.
+#[cfg(test)]
+pub fn test_db() -> anyhow::Result<rusqlite::Connection> {
+    let mut db = rusqlite::Connection::open_in_memory()?;
+    MIGRATIONS.to_latest(&mut db)?;
+    Ok(db)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
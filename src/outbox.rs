@@ -0,0 +1,235 @@
+//! Outgoing chat messages, sent at a rate the server accepts.
+
+use crate::handlers::deserialize_duration;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Maximum number of messages waiting to be sent. Newer messages are dropped when the bot falls
+/// this far behind.
+const QUEUE_LIMIT: usize = 50;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatConfig {
+    /// Longer messages are split up.
+    pub max_length: usize,
+    /// How many messages can be sent per `interval`.
+    pub messages: usize,
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub interval: Duration,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            max_length: 300,
+            messages: 5,
+            interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Split a message into parts of at most `max_length` characters, at spaces where possible.
+fn split_message(message: &str, max_length: usize) -> Vec<String> {
+    let mut parts = vec![];
+    let mut rest = message.trim();
+    while rest.chars().count() > max_length {
+        let (limit, next) = rest.char_indices().nth(max_length).unwrap();
+        // A space right after the limit is a fine place to split, too.
+        match rest[..limit + next.len_utf8()].rfind(char::is_whitespace) {
+            Some(space) if space > 0 => {
+                parts.push(rest[..space].trim_end().to_string());
+                rest = rest[space..].trim_start();
+            }
+            _ => {
+                parts.push(rest[..limit].to_string());
+                rest = &rest[limit..];
+            }
+        }
+    }
+    if !rest.is_empty() {
+        parts.push(rest.to_string());
+    }
+    parts
+}
+
+/// Part of a chat message waiting to be sent.
+#[derive(Debug)]
+struct Part {
+    /// The whole message this part was split from.
+    message: String,
+    text: String,
+}
+
+/// Queue of chat messages that enforces the rate limit. Messages that are already queued, or that
+/// were sent less than an `interval` ago, are dropped.
+#[derive(Debug)]
+pub struct Outbox {
+    config: ChatConfig,
+    queue: VecDeque<Part>,
+    /// Whole messages of the parts sent during the last `interval`.
+    sent: VecDeque<(Instant, String)>,
+}
+
+impl Outbox {
+    pub fn new(config: ChatConfig) -> Self {
+        Self {
+            config,
+            queue: VecDeque::new(),
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// Apply new rate limits. Queued messages are kept as they are.
+    pub fn set_config(&mut self, config: ChatConfig) {
+        self.config = config;
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(sent_at, _)) = self.sent.front() {
+            if now.saturating_duration_since(sent_at) < self.config.interval {
+                break;
+            }
+            self.sent.pop_front();
+        }
+    }
+
+    /// Queue all parts of a message, or none of them if it is a duplicate or does not fit.
+    pub fn push(&mut self, message: &str, now: Instant) {
+        self.expire(now);
+        let message = message.trim();
+        let duplicate = self.queue.iter().any(|part| part.message == message)
+            || self.sent.iter().any(|(_, sent)| sent == message);
+        if duplicate {
+            log::info!("dropping duplicate chat message: {message}");
+            return;
+        }
+        let parts = split_message(message, self.config.max_length.max(1));
+        if self.queue.len() + parts.len() > QUEUE_LIMIT {
+            log::warn!("too many queued chat messages, dropping: {message}");
+            return;
+        }
+        self.queue.extend(parts.into_iter().map(|text| Part {
+            message: message.to_string(),
+            text,
+        }));
+    }
+
+    /// Take the next message if it can be sent now.
+    pub fn pop(&mut self, now: Instant) -> Option<String> {
+        self.expire(now);
+        if self.sent.len() >= self.config.messages {
+            return None;
+        }
+        let Part { message, text } = self.queue.pop_front()?;
+        self.sent.push_back((now, message));
+        Some(text)
+    }
+
+    /// When the next queued message can be sent, if there is one.
+    pub fn next_send(&self, now: Instant) -> Option<Instant> {
+        if self.queue.is_empty() {
+            return None;
+        }
+        if self.sent.len() < self.config.messages {
+            return Some(now);
+        }
+        self.sent
+            .front()
+            .map(|&(sent_at, _)| sent_at + self.config.interval)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split_message, ChatConfig, Outbox};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn split() {
+        assert_eq!(split_message("  short  ", 10), ["short"]);
+        assert_eq!(
+            split_message("the quick brown fox jumps", 10),
+            ["the quick", "brown fox", "jumps"]
+        );
+        assert_eq!(split_message("0123456789 next", 10), ["0123456789", "next"]);
+        assert_eq!(
+            split_message("https://wlk.yt/a/very/long/url", 10),
+            ["https://wl", "k.yt/a/ver", "y/long/url"]
+        );
+        assert_eq!(
+            split_message("아이유 아이유 아이유", 7),
+            ["아이유 아이유", "아이유"]
+        );
+        assert!(split_message("   ", 10).is_empty());
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut outbox = Outbox::new(ChatConfig {
+            max_length: 20,
+            messages: 2,
+            interval: Duration::from_secs(10),
+        });
+        let start = Instant::now();
+        assert_eq!(outbox.next_send(start), None);
+
+        outbox.push("one", start);
+        outbox.push("two", start);
+        outbox.push("one", start);
+        outbox.push("three is a long message", start);
+        assert_eq!(outbox.len(), 4);
+        assert_eq!(outbox.next_send(start), Some(start));
+        assert_eq!(outbox.pop(start).as_deref(), Some("one"));
+        assert_eq!(outbox.pop(start).as_deref(), Some("two"));
+        assert_eq!(outbox.pop(start), None);
+        assert_eq!(
+            outbox.next_send(start),
+            Some(start + Duration::from_secs(10))
+        );
+
+        // Recently sent messages are not repeated.
+        outbox.push("two", start + Duration::from_secs(5));
+        assert_eq!(outbox.len(), 2);
+
+        let later = start + Duration::from_secs(10);
+        assert_eq!(outbox.pop(later).as_deref(), Some("three is a long"));
+        assert_eq!(outbox.pop(later).as_deref(), Some("message"));
+        assert_eq!(outbox.pop(later), None);
+        assert_eq!(outbox.next_send(later), None);
+    }
+
+    #[test]
+    fn repeated_parts() {
+        let mut outbox = Outbox::new(ChatConfig {
+            max_length: 4,
+            messages: 5,
+            interval: Duration::from_secs(10),
+        });
+        let now = Instant::now();
+
+        outbox.push("haha haha haha", now);
+        outbox.push("haha", now);
+        assert_eq!(outbox.len(), 4);
+        // The whole message is a duplicate, even after some of its parts were sent.
+        assert_eq!(outbox.pop(now).as_deref(), Some("haha"));
+        outbox.push("haha haha haha", now);
+        assert_eq!(outbox.len(), 3);
+        assert_eq!(outbox.pop(now).as_deref(), Some("haha"));
+        assert_eq!(outbox.pop(now).as_deref(), Some("haha"));
+        assert_eq!(outbox.pop(now).as_deref(), Some("haha"));
+        assert_eq!(outbox.pop(now), None);
+
+        // Messages that do not fit in the queue are dropped whole.
+        for i in 0..49 {
+            outbox.push(&i.to_string(), now + Duration::from_secs(10));
+        }
+        outbox.push("one two", now + Duration::from_secs(10));
+        assert_eq!(outbox.len(), 49);
+    }
+}
@@ -0,0 +1,46 @@
+//! Where pages like the emote list and the karma leaderboard are published.
+
+use crate::api::neocities;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Publisher {
+    /// Upload pages to a Neocities site.
+    Neocities {
+        #[serde(default)]
+        username: String,
+        #[serde(default)]
+        password: String,
+    },
+    /// Write pages to a local directory that is served at `url`.
+    Directory { path: PathBuf, url: String },
+}
+
+impl Default for Publisher {
+    fn default() -> Self {
+        Self::Neocities {
+            username: String::new(),
+            password: String::new(),
+        }
+    }
+}
+
+impl Publisher {
+    /// Publish an HTML page, returning its URL.
+    pub fn publish(&self, page_name: &str, content: &str) -> Result<String> {
+        match self {
+            Self::Neocities { username, password } => {
+                Ok(neocities::publish(username, password, page_name, content)?)
+            }
+            Self::Directory { path, url } => {
+                let file = path.join(page_name);
+                std::fs::write(&file, content)
+                    .with_context(|| format!("could not write {}", file.display()))?;
+                Ok(format!("{}/{page_name}", url.trim_end_matches('/')))
+            }
+        }
+    }
+}
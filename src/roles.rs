@@ -0,0 +1,113 @@
+use crate::api::uwave::HttpApi;
+use crate::handler::MessageType;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// Check if a user with `roles` has the `required` role, either directly or through a role that
+/// includes it.
+pub fn includes_role(
+    hierarchy: &HashMap<String, Vec<String>>,
+    roles: &[String],
+    required: &str,
+) -> bool {
+    let mut seen = HashSet::new();
+    let mut queue: Vec<&str> = roles.iter().map(String::as_str).collect();
+    while let Some(role) = queue.pop() {
+        if role == required || role == "*" {
+            return true;
+        }
+        if seen.insert(role) {
+            if let Some(included) = hierarchy.get(role) {
+                queue.extend(included.iter().map(String::as_str));
+            }
+        }
+    }
+    false
+}
+
+/// Roles of users that are not online, fetched from the HTTP API.
+#[derive(Debug, Clone, Default)]
+pub struct RoleCache {
+    users: Arc<Mutex<HashMap<String, Vec<String>>>>,
+}
+
+impl RoleCache {
+    /// Keep cached roles up to date with role change events.
+    pub fn apply(&self, message: &MessageType) {
+        let mut users = self.users.lock().unwrap();
+        match message {
+            MessageType::UserJoin(user) => {
+                users.insert(user.id.clone(), user.roles.clone());
+            }
+            MessageType::RolesAdded { user_id, roles } => {
+                if let Some(cached) = users.get_mut(user_id) {
+                    for role in roles {
+                        if !cached.contains(role) {
+                            cached.push(role.clone());
+                        }
+                    }
+                }
+            }
+            MessageType::RolesRemoved { user_id, roles } => {
+                if let Some(cached) = users.get_mut(user_id) {
+                    cached.retain(|role| !roles.contains(role));
+                }
+            }
+            MessageType::Reconnected(_) => users.clear(),
+            _ => (),
+        }
+    }
+
+    /// Get the roles of a user that is not online. They are fetched once and then cached.
+    pub fn roles(&self, http: &HttpApi, user_id: &str) -> anyhow::Result<Vec<String>> {
+        if let Some(roles) = self.users.lock().unwrap().get(user_id) {
+            return Ok(roles.clone());
+        }
+
+        let user = http.user(user_id)?;
+        self.users
+            .lock()
+            .unwrap()
+            .insert(user.id, user.roles.clone());
+        Ok(user.roles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::includes_role;
+    use std::collections::HashMap;
+
+    #[test]
+    fn role_hierarchy() {
+        let hierarchy: HashMap<String, Vec<String>> = serde_json::from_value(serde_json::json!({
+            "admin": ["*"],
+            "manager": ["moderator", "waitlist.clear"],
+            "moderator": ["user", "booth.skip.other"],
+            "special": ["user"],
+            "user": ["waitlist.join"],
+        }))
+        .unwrap();
+        let roles = |roles: &[&str]| {
+            roles
+                .iter()
+                .map(|role| role.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(includes_role(&hierarchy, &roles(&["manager"]), "moderator"));
+        assert!(includes_role(&hierarchy, &roles(&["manager"]), "user"));
+        assert!(includes_role(&hierarchy, &roles(&["admin"]), "manager"));
+        assert!(includes_role(
+            &hierarchy,
+            &roles(&["user", "special"]),
+            "special"
+        ));
+        assert!(!includes_role(
+            &hierarchy,
+            &roles(&["special"]),
+            "moderator"
+        ));
+        assert!(!includes_role(&hierarchy, &roles(&[]), "user"));
+    }
+}
@@ -0,0 +1,29 @@
+//! Runtime settings that handlers persist in the database, stored as strings per namespace.
+
+use anyhow::Result;
+use rusqlite::{params, Connection};
+
+/// Get all stored settings in a namespace.
+pub fn load(db: &Connection, namespace: &str) -> Result<Vec<(String, String)>> {
+    let mut stmt =
+        db.prepare("SELECT key, value FROM settings WHERE namespace = ? ORDER BY key")?;
+    let query = stmt.query_map([namespace], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    Ok(query.collect::<Result<_, _>>()?)
+}
+
+pub fn store(db: &Connection, namespace: &str, key: &str, value: &str) -> Result<()> {
+    db.execute(
+        "INSERT INTO settings (namespace, key, value) VALUES (?1, ?2, ?3)
+        ON CONFLICT (namespace, key) DO UPDATE SET value = ?3",
+        params![namespace, key, value],
+    )?;
+    Ok(())
+}
+
+pub fn remove(db: &Connection, namespace: &str, key: &str) -> Result<()> {
+    db.execute(
+        "DELETE FROM settings WHERE namespace = ? AND key = ?",
+        [namespace, key],
+    )?;
+    Ok(())
+}
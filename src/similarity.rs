@@ -0,0 +1,43 @@
+//! String similarity for fuzzy matching.
+
+/// The number of single character insertions, deletions or substitutions needed to turn `a` into
+/// `b`.
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(a != *b);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// How similar two strings are, from 0 (nothing in common) to 1 (identical).
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let longest = a.chars().count().max(b.chars().count());
+    if longest == 0 {
+        return 1.0;
+    }
+    1.0 - levenshtein(a, b) as f64 / longest as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{levenshtein, similarity};
+
+    #[test]
+    fn edit_distance() {
+        assert_eq!(levenshtein("", ""), 0);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("palette", ""), 7);
+        assert_eq!(levenshtein("아이유", "아이유"), 0);
+        assert_eq!(levenshtein("아이유", "아이"), 1);
+        assert_eq!(similarity("abcd", "abcx"), 0.75);
+        assert_eq!(similarity("", ""), 1.0);
+    }
+}
@@ -0,0 +1,672 @@
+//! Runs handlers on a pool of threads. Each handler sees messages one at a time and in the order
+//! they were received, but different handlers run in parallel, so a slow handler does not hold up
+//! the others.
+
+use crate::command::{Commands, Dispatch};
+use crate::config::Config;
+use crate::handler::{Api, Handler, MessageType};
+use crate::handlers::{deserialize_duration, Modules};
+use crate::UnauthorizedError;
+use flume::{Receiver, Sender};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Maximum number of messages waiting for a single handler. Newer messages are dropped when a
+/// handler falls this far behind. Reloads and shutdowns are always queued.
+const QUEUE_LIMIT: usize = 100;
+/// How often to check for slow handlers while any are running.
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkerConfig {
+    /// Number of threads running handlers.
+    pub threads: usize,
+    /// Log a warning when a handler takes longer than this for a single message.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub slow: Duration,
+    /// Stop waiting for a handler that takes longer than this for a single message.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub timeout: Duration,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            threads: 4,
+            slow: Duration::from_secs(2),
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Mention a user at the start of a reply, or nothing if their username can not be looked up.
+fn mention(api: &Api, user_id: &str) -> String {
+    match api.username(user_id) {
+        Ok(username) => format!("@{username} "),
+        Err(err) => {
+            log::warn!("could not look up user {user_id}: {err}");
+            String::new()
+        }
+    }
+}
+
+/// Call the handler for a chat command, if the user is allowed to use it.
+fn handle_command(
+    api: &Api,
+    handler: &mut dyn Handler,
+    commands: &Commands,
+    message: &MessageType,
+) -> anyhow::Result<()> {
+    let MessageType::ChatMessage(message) = message else {
+        return Ok(());
+    };
+    let Some(Dispatch::Handler(_, invocation)) = commands.dispatch(message) else {
+        return Ok(());
+    };
+
+    if let Some(role) = invocation.command.role {
+        if !api.has_role(&message.user_id, role)? {
+            let mention = mention(api, &message.user_id);
+            api.send_message(format_args!(
+                "{mention}You need the {role} role to use {}{}.",
+                invocation.prefix, invocation.command.name
+            ));
+            return Ok(());
+        }
+    }
+
+    handler.handle_command(api.clone(), &invocation)
+}
+
+/// Tell the user that their command waited too long to be handled. The room has changed since, so
+/// handling it now could act on the wrong user or song.
+fn expire_command(api: &Api, commands: &Commands, message: &MessageType, module: &str) {
+    let MessageType::ChatMessage(message) = message else {
+        return;
+    };
+    let Some(Dispatch::Handler(_, invocation)) = commands.dispatch(message) else {
+        return;
+    };
+    let mention = mention(api, &message.user_id);
+    api.send_message(format_args!(
+        "{mention}The {module} module was too busy to handle {}{} in time. Please try again.",
+        invocation.prefix, invocation.command.name
+    ));
+}
+
+/// Send a handler error to chat. Returns the error if the bot should exit.
+fn report_handler_error(api: &Api, result: anyhow::Result<()>) -> anyhow::Result<()> {
+    match result {
+        Ok(()) => Ok(()),
+        // Exit if we are no longer authenticated so the bot can be restarted
+        Err(err) if err.is::<UnauthorizedError>() => {
+            api.exit();
+            Err(err)
+        }
+        Err(err) => {
+            api.send_message(format_args!("Could not handle message: {err}"));
+            Ok(())
+        }
+    }
+}
+
+enum Work {
+    /// Run the next task for the handler at this index.
+    Run(usize),
+    Stop,
+}
+
+enum Watch {
+    /// Check running tasks. Sent when a task starts.
+    Check,
+    Stop,
+}
+
+enum TaskKind {
+    Message(Arc<MessageType>),
+    /// Call the command handler for the chat command in the message.
+    Command(Arc<MessageType>),
+    Reload(Arc<Config>),
+    Shutdown,
+}
+
+struct Task {
+    api: Api,
+    kind: TaskKind,
+    queued_at: Instant,
+}
+
+impl Task {
+    fn new(api: &Api, kind: TaskKind) -> Self {
+        Self {
+            api: api.clone(),
+            kind,
+            queued_at: Instant::now(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    tasks: VecDeque<Task>,
+    /// Set while the handler is waiting for or running on a worker, so it only ever runs on one
+    /// worker at a time.
+    scheduled: bool,
+}
+
+struct Slot {
+    name: &'static str,
+    handler: Mutex<Box<dyn Handler + Send>>,
+    queue: Mutex<Queue>,
+}
+
+/// A task that a worker is busy with.
+struct Running {
+    slot: usize,
+    api: Api,
+    started: Instant,
+    warned: bool,
+    timed_out: bool,
+}
+
+struct Shared {
+    slots: Vec<Slot>,
+    commands: RwLock<Commands>,
+    config: WorkerConfig,
+    ready_sender: Sender<Work>,
+    ready_receiver: Receiver<Work>,
+    watch_sender: Sender<Watch>,
+    /// Receives errors that should stop the bot.
+    fatal: Sender<anyhow::Error>,
+    running: Mutex<HashMap<usize, Running>>,
+    workers: Mutex<Vec<(usize, JoinHandle<()>)>>,
+    next_worker: AtomicUsize,
+}
+
+impl Shared {
+    fn spawn_worker(self: &Arc<Self>) {
+        let id = self.next_worker.fetch_add(1, Ordering::Relaxed);
+        let shared = Arc::clone(self);
+        let handle = std::thread::Builder::new()
+            .name(format!("worker-{id}"))
+            .spawn(move || shared.work(id))
+            .expect("could not spawn worker thread");
+        self.workers.lock().unwrap().push((id, handle));
+    }
+
+    fn work(&self, id: usize) {
+        while let Ok(Work::Run(index)) = self.ready_receiver.recv() {
+            let slot = &self.slots[index];
+            let task = {
+                let mut queue = slot.queue.lock().unwrap();
+                match queue.tasks.pop_front() {
+                    Some(task) => task,
+                    None => {
+                        queue.scheduled = false;
+                        continue;
+                    }
+                }
+            };
+
+            self.running.lock().unwrap().insert(
+                id,
+                Running {
+                    slot: index,
+                    api: task.api.clone(),
+                    started: Instant::now(),
+                    warned: false,
+                    timed_out: false,
+                },
+            );
+            let _ = self.watch_sender.send(Watch::Check);
+
+            let result = {
+                let mut handler = slot.handler.lock().unwrap();
+                let api = task.api.clone();
+                match &task.kind {
+                    TaskKind::Message(message) => handler.handle(api, message),
+                    TaskKind::Command(message) => {
+                        let commands = self.commands.read().unwrap();
+                        if task.queued_at.elapsed() >= self.config.timeout {
+                            expire_command(&api, &commands, message, slot.name);
+                            Ok(())
+                        } else {
+                            handle_command(&api, &mut **handler, &commands, message)
+                        }
+                    }
+                    TaskKind::Reload(config) => handler.reload(api, config),
+                    TaskKind::Shutdown => handler.shutdown(api),
+                }
+            };
+
+            let running = self.running.lock().unwrap().remove(&id);
+            if let Some(Running {
+                started, warned, ..
+            }) = running
+            {
+                let elapsed = started.elapsed();
+                if warned || elapsed >= self.config.slow {
+                    log::warn!("{} took {elapsed:?} to handle a message", slot.name);
+                }
+            }
+            if let Err(err) = report_handler_error(&task.api, result) {
+                let _ = self.fatal.send(err);
+            }
+
+            {
+                let mut queue = slot.queue.lock().unwrap();
+                if queue.tasks.is_empty() {
+                    queue.scheduled = false;
+                } else {
+                    let _ = self.ready_sender.send(Work::Run(index));
+                }
+            }
+
+            // A replacement was started while this worker was stuck.
+            if running.is_some_and(|running| running.timed_out) {
+                break;
+            }
+        }
+    }
+
+    /// Whether any worker is busy with a task that has not timed out yet.
+    fn busy(&self) -> bool {
+        self.running
+            .lock()
+            .unwrap()
+            .values()
+            .any(|running| !running.timed_out)
+    }
+
+    /// Check running tasks until told to stop, only waking up periodically while tasks are
+    /// running.
+    fn watch(self: &Arc<Self>, receiver: Receiver<Watch>) {
+        loop {
+            let event = if self.busy() {
+                match receiver.recv_timeout(WATCHDOG_INTERVAL) {
+                    Err(flume::RecvTimeoutError::Timeout) => Ok(Watch::Check),
+                    Err(flume::RecvTimeoutError::Disconnected) => Ok(Watch::Stop),
+                    Ok(event) => Ok(event),
+                }
+            } else {
+                receiver.recv()
+            };
+            match event {
+                Ok(Watch::Check) => self.check(),
+                Ok(Watch::Stop) | Err(_) => break,
+            }
+        }
+    }
+
+    /// Warn about slow handlers, and replace workers that are stuck on a handler for too long.
+    fn check(self: &Arc<Self>) {
+        let mut replacements = 0;
+        for running in self.running.lock().unwrap().values_mut() {
+            let name = self.slots[running.slot].name;
+            let elapsed = running.started.elapsed();
+            if !running.warned && elapsed >= self.config.slow {
+                running.warned = true;
+                log::warn!("{name} is slow, still handling a message after {elapsed:?}");
+            }
+            if !running.timed_out && elapsed >= self.config.timeout {
+                running.timed_out = true;
+                replacements += 1;
+                log::error!("{name} timed out after {elapsed:?}");
+                running.api.send_message(format_args!(
+                    "The {name} module is taking too long and will handle further messages late."
+                ));
+            }
+        }
+
+        for _ in 0..replacements {
+            self.spawn_worker();
+        }
+    }
+}
+
+/// Runs handlers on a pool of threads.
+pub struct WorkerPool {
+    shared: Arc<Shared>,
+    modules: Modules,
+    watchdog: Option<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(
+        handlers: Vec<Box<dyn Handler + Send>>,
+        commands: Commands,
+        modules: Modules,
+        config: WorkerConfig,
+        fatal: Sender<anyhow::Error>,
+    ) -> Self {
+        let (ready_sender, ready_receiver) = flume::unbounded();
+        let (watch_sender, watch_receiver) = flume::unbounded();
+        let threads = config.threads.max(1);
+        let shared = Arc::new(Shared {
+            slots: handlers
+                .into_iter()
+                .map(|handler| Slot {
+                    name: handler.name(),
+                    handler: Mutex::new(handler),
+                    queue: Default::default(),
+                })
+                .collect(),
+            commands: RwLock::new(commands),
+            config,
+            ready_sender,
+            ready_receiver,
+            watch_sender,
+            fatal,
+            running: Default::default(),
+            workers: Default::default(),
+            next_worker: AtomicUsize::new(0),
+        });
+
+        for _ in 0..threads {
+            shared.spawn_worker();
+        }
+
+        let watchdog = {
+            let shared = Arc::clone(&shared);
+            std::thread::spawn(move || shared.watch(watch_receiver))
+        };
+
+        Self {
+            shared,
+            modules,
+            watchdog: Some(watchdog),
+        }
+    }
+
+    fn enqueue(&self, index: usize, task: Task) {
+        let slot = &self.shared.slots[index];
+        let mut queue = slot.queue.lock().unwrap();
+        let droppable = matches!(task.kind, TaskKind::Message(_) | TaskKind::Command(_));
+        if droppable && queue.tasks.len() >= QUEUE_LIMIT {
+            log::warn!("{} is too far behind, dropping message", slot.name);
+            return;
+        }
+        queue.tasks.push_back(task);
+        if !queue.scheduled {
+            queue.scheduled = true;
+            let _ = self.shared.ready_sender.send(Work::Run(index));
+        }
+    }
+
+    /// Queue a message for all enabled handlers, and for the handler of the chat command in it.
+    pub fn handle(&self, api: &Api, message: MessageType) {
+        let message = Arc::new(message);
+        for (index, slot) in self.shared.slots.iter().enumerate() {
+            if !self.modules.is_enabled(slot.name) {
+                continue;
+            }
+            self.enqueue(
+                index,
+                Task::new(api, TaskKind::Message(Arc::clone(&message))),
+            );
+        }
+
+        let MessageType::ChatMessage(chat_message) = &*message else {
+            return;
+        };
+        let commands = self.shared.commands.read().unwrap();
+        match commands.dispatch(chat_message) {
+            Some(Dispatch::Handler(index, _)) => {
+                let name = self.shared.slots[index].name;
+                if !self.modules.is_enabled(name) {
+                    log::debug!("ignoring command for disabled module {name}");
+                    return;
+                }
+                self.enqueue(
+                    index,
+                    Task::new(api, TaskKind::Command(Arc::clone(&message))),
+                );
+            }
+            Some(Dispatch::Reply(reply)) => api.send_message(reply),
+            None => (),
+        }
+    }
+
+    /// Pass a reloaded configuration to all handlers, after the messages they already received.
+    pub fn reload(&self, api: &Api, config: Arc<Config>) {
+        self.shared
+            .commands
+            .write()
+            .unwrap()
+            .set_prefix(&config.command_prefix);
+        for index in 0..self.shared.slots.len() {
+            self.enqueue(index, Task::new(api, TaskKind::Reload(Arc::clone(&config))));
+        }
+    }
+
+    /// Wait until all queued tasks are done, except for handlers that timed out.
+    fn wait_idle(&self) {
+        let shared = &self.shared;
+        loop {
+            let stuck: Vec<usize> = shared
+                .running
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|running| running.timed_out)
+                .map(|running| running.slot)
+                .collect();
+            let pending = shared.slots.iter().enumerate().any(|(index, slot)| {
+                slot.queue.lock().unwrap().scheduled && !stuck.contains(&index)
+            });
+            if !pending {
+                break;
+            }
+            std::thread::sleep(WATCHDOG_INTERVAL);
+        }
+    }
+
+    /// Finish the queued messages, let the handlers know that the bot is shutting down, and stop
+    /// the workers. Does not wait for handlers that timed out.
+    pub fn shutdown(mut self, api: &Api) {
+        self.wait_idle();
+        for index in 0..self.shared.slots.len() {
+            self.enqueue(index, Task::new(api, TaskKind::Shutdown));
+        }
+        self.wait_idle();
+
+        let shared = &self.shared;
+        let _ = shared.watch_sender.send(Watch::Stop);
+        if let Some(watchdog) = self.watchdog.take() {
+            watchdog.join().unwrap();
+        }
+
+        let timed_out: Vec<usize> = shared
+            .running
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, running)| running.timed_out)
+            .map(|(&id, _)| id)
+            .collect();
+        let workers: Vec<_> = shared
+            .workers
+            .lock()
+            .unwrap()
+            .drain(..)
+            .filter(|(id, _)| !timed_out.contains(id))
+            .collect();
+        for _ in &workers {
+            let _ = shared.ready_sender.send(Work::Stop);
+        }
+        for (_, worker) in workers {
+            worker.join().unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WorkerConfig, WorkerPool};
+    use crate::api::uwave::{HttpApi, NowState};
+    use crate::command::{Command, Commands, Invocation};
+    use crate::config::Config;
+    use crate::handler::{Api, ApiMessage, ApiSender, ChatMessage, Handler, MessageType};
+    use crate::handlers::Modules;
+    use crate::migrations::test_db;
+    use crate::roles::RoleCache;
+    use anyhow::Result;
+    use polling::Poller;
+    use r2d2_sqlite::SqliteConnectionManager;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::{Duration, Instant};
+    use ureq::AgentBuilder;
+
+    const RELOADED: u64 = u64::MAX - 1;
+    const SHUT_DOWN: u64 = u64::MAX;
+
+    /// Records the messages it sees, sleeping for the number of milliseconds in the message.
+    #[derive(Debug)]
+    struct Sleepy {
+        name: &'static str,
+        seen: Arc<Mutex<Vec<(&'static str, u64)>>>,
+    }
+
+    impl Handler for Sleepy {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn commands(&self) -> &'static [Command] {
+            const COMMANDS: &[Command] = &[Command::new("sleepy", "Sleep.")];
+            if self.name == "a" {
+                COMMANDS
+            } else {
+                &[]
+            }
+        }
+
+        fn handle_command(&mut self, _api: Api, _invocation: &Invocation) -> Result<()> {
+            self.seen.lock().unwrap().push((self.name, 0));
+            Ok(())
+        }
+
+        fn handle(&mut self, _api: Api, message: &MessageType) -> Result<()> {
+            if let MessageType::Guests { count } = message {
+                if self.name == "a" {
+                    std::thread::sleep(Duration::from_millis(*count as u64));
+                }
+                self.seen.lock().unwrap().push((self.name, *count as u64));
+            }
+            Ok(())
+        }
+
+        fn reload(&mut self, _api: Api, _config: &Config) -> Result<()> {
+            self.seen.lock().unwrap().push((self.name, RELOADED));
+            Ok(())
+        }
+
+        fn shutdown(&mut self, _api: Api) -> Result<()> {
+            self.seen.lock().unwrap().push((self.name, SHUT_DOWN));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn ordering_and_timeouts() -> Result<()> {
+        let db = test_db()?;
+        let (sender, receiver) = flume::unbounded();
+        let api = Api::new(
+            ApiSender::new(sender, flume::unbounded().0, Arc::new(Poller::new()?)),
+            r2d2::Pool::new(SqliteConnectionManager::memory())?,
+            Arc::new(RwLock::new(serde_json::from_value::<NowState>(
+                serde_json::json!({ "users": [{ "_id": "1", "username": "user" }] }),
+            )?)),
+            RoleCache::default(),
+            HttpApi::anonymous(AgentBuilder::new().build(), "http://localhost".into()),
+            Default::default(),
+        );
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let handlers: Vec<Box<dyn Handler + Send>> = vec![
+            Box::new(Sleepy {
+                name: "a",
+                seen: Arc::clone(&seen),
+            }),
+            Box::new(Sleepy {
+                name: "b",
+                seen: Arc::clone(&seen),
+            }),
+        ];
+        let commands = Commands::new(&handlers, "!");
+        let modules = Modules::load(&db, vec!["a", "b"])?;
+        let (fatal, _fatal_receiver) = flume::unbounded();
+        let workers = WorkerPool::new(
+            handlers,
+            commands,
+            modules,
+            WorkerConfig {
+                threads: 2,
+                slow: Duration::from_millis(100),
+                timeout: Duration::from_millis(500),
+            },
+            fatal,
+        );
+        // Waits until a handler has seen a message, with a generous deadline.
+        let wait_for = |name: &'static str, count: u64| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !seen.lock().unwrap().contains(&(name, count)) {
+                assert!(Instant::now() < deadline, "{} never saw {}", name, count);
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        };
+        let seen_by = |name: &str| -> Vec<u64> {
+            let seen = seen.lock().unwrap();
+            seen.iter()
+                .filter(|(seen_name, _)| *seen_name == name)
+                .map(|&(_, count)| count)
+                .collect()
+        };
+
+        workers.handle(&api, MessageType::Guests { count: 50 });
+        workers.handle(&api, MessageType::Guests { count: 1 });
+        workers.handle(
+            &api,
+            MessageType::ChatMessage(ChatMessage::test("1", "!sleepy")),
+        );
+        wait_for("b", 1);
+        // Reloading happens after the messages that were already queued.
+        workers.reload(&api, Arc::new(Config::default()));
+
+        // a gets stuck and is replaced, so b keeps going. The command queued behind it expires.
+        workers.handle(&api, MessageType::Guests { count: 1500 });
+        workers.handle(
+            &api,
+            MessageType::ChatMessage(ChatMessage::test("1", "!sleepy")),
+        );
+        workers.handle(&api, MessageType::Guests { count: 2 });
+        workers.handle(&api, MessageType::Guests { count: 3 });
+        let ApiMessage::SendChat(message) = receiver.recv_timeout(Duration::from_secs(5))? else {
+            panic!("expected a timeout message")
+        };
+        assert_eq!(
+            message,
+            "The a module is taking too long and will handle further messages late."
+        );
+        wait_for("b", 3);
+        assert!(!seen_by("a").contains(&1500));
+
+        let ApiMessage::SendChat(message) = receiver.recv_timeout(Duration::from_secs(5))? else {
+            panic!("expected an expired command message")
+        };
+        assert_eq!(
+            message,
+            "@user The a module was too busy to handle !sleepy in time. Please try again."
+        );
+
+        workers.shutdown(&api);
+        assert_eq!(seen_by("a"), [50, 1, 0, RELOADED, 1500, 2, 3, SHUT_DOWN]);
+        assert_eq!(seen_by("b"), [50, 1, RELOADED, 1500, 2, 3, SHUT_DOWN]);
+        Ok(())
+    }
+}